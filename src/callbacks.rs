@@ -1,4 +1,19 @@
 use super::*;
+use diff::EditName;
+use print_analyzer::{
+    bounds::OutOfBounds,
+    collision::Collision,
+    deform::{DeformReport, Expr, HeightMap, Surface},
+    edit::Feature,
+    feedrate::FeedrateOp,
+    limits::Violation,
+    retraction::{RetractParams, RetractionReport},
+    schedule::{LayerValue, Parameter, Schedule},
+    seams::{SeamAlignment, SeamStats},
+    transform::Affine,
+    wipe::WipeParams,
+    zhop::ZHopParams,
+};
 use std::collections::HashSet;
 
 #[derive(Default, Resource)]
@@ -10,6 +25,127 @@ pub struct HoleDelete;
 #[derive(Default, Resource)]
 pub struct SubdivideSelection(pub u32);
 
+// pauses at the start of each layer height
+#[derive(Default, Resource)]
+pub struct InsertPause(pub Vec<f32>);
+
+#[derive(Resource)]
+pub struct PreviewSchedule(pub Parameter, pub Schedule);
+
+#[derive(Default, Resource)]
+pub struct SchedulePreview(pub Vec<LayerValue>);
+
+#[derive(Resource)]
+pub struct ApplySchedule(pub Parameter, pub Schedule);
+
+// changes the feedrate of the selection, expanded to shapes or layers
+#[derive(Resource)]
+pub struct SetFeedrate(pub FeedrateOp);
+
+// multiplies the extrusion of the selection, expanded to shapes or layers
+#[derive(Resource)]
+pub struct ScaleFlow(pub f32);
+
+// mirrors, scales, shears or applies a matrix to the selection about its center
+#[derive(Resource)]
+pub struct ApplyAffine(pub Affine);
+
+// moves and turns the whole print on the bed, leaving start and end gcode in place
+#[derive(Resource)]
+pub struct MovePrint {
+    pub dx: f32,
+    pub dy: f32,
+    pub angle: f32,
+}
+
+// pushes the selection up and down by the surface set up in the ui
+#[derive(Default, Resource)]
+pub struct DeformSelection;
+
+#[derive(Resource)]
+pub struct DeformResult(pub DeformReport);
+
+// places the custom gcode text around each selected vertex, shape or layer
+#[derive(Default, Resource)]
+pub struct InsertGCode {
+    pub after: bool,
+}
+
+#[derive(Default, Resource)]
+pub struct CheckLimits;
+
+#[derive(Default, Resource)]
+pub struct ClampLimits;
+
+#[derive(Default, Resource)]
+pub struct LimitReport(pub Vec<Violation>);
+
+#[derive(Default, Resource)]
+pub struct Save {
+    // skip the build volume check
+    pub force: bool,
+}
+
+#[derive(Resource)]
+pub struct SaveWarning(pub Vec<OutOfBounds>);
+
+// the moves that would have ended up off the bed when the print was refused a move
+#[derive(Resource)]
+pub struct MovePrintWarning(pub Vec<OutOfBounds>);
+
+#[derive(Default, Resource)]
+pub struct ExportSvg {
+    // one file per layer instead of the displayed layer only
+    pub all: bool,
+}
+
+#[derive(Default, Resource)]
+pub struct CheckCollisions;
+
+#[derive(Default, Resource)]
+pub struct CollisionReport(pub Vec<Collision>);
+
+// lifts the nozzle over long travels and travels across printed material
+#[derive(Resource)]
+pub struct AddZHops(pub ZHopParams);
+
+#[derive(Default, Resource)]
+pub struct AnalyzeRetractions;
+
+// retracts on long travels and takes retractions off short ones
+#[derive(Resource)]
+pub struct SetRetractions(pub RetractParams);
+
+// moves back over the path just printed while retracting
+#[derive(Resource)]
+pub struct AddWipes(pub WipeParams);
+
+#[derive(Default, Resource)]
+pub struct RetractionAnalysis(pub RetractionReport);
+
+#[derive(Default, Resource)]
+pub struct AnalyzeSeams;
+
+#[derive(Default, Resource)]
+pub struct SeamAnalysis(pub Vec<SeamStats>);
+
+// start every selected loop at the selected vertex
+#[derive(Default, Resource)]
+pub struct SeamsToSelection;
+
+#[derive(Resource)]
+pub struct AlignSeams(pub SeamAlignment);
+
+#[derive(Default, Resource)]
+pub struct ClassifySupport;
+
+#[derive(Default, Resource)]
+pub struct SlowUnsupported;
+
+// replaces the current selection
+#[derive(Default, Resource)]
+pub struct SelectIds(pub HashSet<Id>);
+
 fn get_selections(mut s_query: Query<(&PickSelection, &Tag)>) -> HashSet<Id> {
     s_query
         .iter_mut()
@@ -18,12 +154,50 @@ fn get_selections(mut s_query: Query<(&PickSelection, &Tag)>) -> HashSet<Id> {
         .collect::<HashSet<Id>>()
 }
 
+// the selected vertices, or every line of their shapes or layers
+fn expand_selection(gcode: &Parsed, selection: &HashSet<Id>, choice: Choice) -> HashSet<Id> {
+    let mut lines = HashSet::new();
+    for id in selection {
+        match choice {
+            Choice::Vertex => {
+                lines.insert(*id);
+            }
+            Choice::Shape => lines.extend(gcode.get_shape(id)),
+            Choice::Layer => lines.extend(gcode.get_same_z(id)),
+        }
+    }
+    lines
+}
+
+// the moves in the expanded selection and the matrix for the transform about their center
+fn affine_matrix(
+    gcode: &Parsed,
+    selection: &HashSet<Id>,
+    choice: Choice,
+    affine: Affine,
+) -> (HashSet<Id>, Mat4) {
+    let vertices = expand_selection(gcode, selection, choice)
+        .into_iter()
+        .filter(|id| gcode.vertices.contains_key(id))
+        .collect::<HashSet<Id>>();
+    let origin = if vertices.is_empty() {
+        Vec3::ZERO
+    } else {
+        gcode.get_centroid(&vertices)
+    };
+    (vertices, affine.matrix(origin))
+}
+
 pub fn merge_delete(
     mut commands: Commands,
     mut gcode: ResMut<GCode>,
     s_query: Query<(&PickSelection, &Tag)>,
 ) {
     let mut selection = get_selections(s_query);
+    commands.insert_resource(EditName(format!(
+        "Merge delete {} vertices",
+        selection.len()
+    )));
     gcode.0.merge_delete(&mut selection);
     commands.init_resource::<ForceRefresh>();
     commands.remove_resource::<MergeDelete>();
@@ -32,10 +206,15 @@ pub fn merge_delete(
 pub fn hole_delete(
     mut commands: Commands,
     mut gcode: ResMut<GCode>,
+    ui_res: Res<UiResource>,
     s_query: Query<(&PickSelection, &Tag)>,
 ) {
     let mut selection = get_selections(s_query);
-    gcode.0.hole_delete(&mut selection);
+    commands.insert_resource(EditName(format!(
+        "Hole delete {} vertices",
+        selection.len()
+    )));
+    gcode.0.hole_delete(&mut selection, &ui_res.retract);
     commands.init_resource::<ForceRefresh>();
     commands.remove_resource::<HoleDelete>();
 }
@@ -47,7 +226,458 @@ pub fn subdivide_selection(
     count: Res<SubdivideSelection>,
 ) {
     let selection = get_selections(s_query);
+    commands.insert_resource(EditName(format!(
+        "Subdivide {} vertices into {}",
+        selection.len(),
+        count.0
+    )));
     gcode.0.subdivide_vertices(selection, count.0);
     commands.init_resource::<ForceRefresh>();
     commands.remove_resource::<SubdivideSelection>();
 }
+
+pub fn insert_gcode(
+    mut commands: Commands,
+    mut gcode: ResMut<GCode>,
+    ui_res: Res<UiResource>,
+    s_query: Query<(&PickSelection, &Tag)>,
+    insert: Res<InsertGCode>,
+) {
+    commands.remove_resource::<InsertGCode>();
+    let selection = get_selections(s_query);
+    let gcode = &mut gcode.0;
+    let feature = Feature::Vertices(expand_selection(gcode, &selection, ui_res.selection_enum));
+    let (result, place) = if insert.after {
+        (gcode.insert_after(&feature, &ui_res.gcode_emit), "after")
+    } else {
+        (gcode.insert_before(&feature, &ui_res.gcode_emit), "before")
+    };
+    match result {
+        Ok(lines) => {
+            commands.insert_resource(EditName(format!(
+                "Insert {} lines {} selection",
+                lines.len(),
+                place
+            )));
+            commands.init_resource::<ForceRefresh>();
+        }
+        Err(e) => println!("failed to insert gcode: {}", e),
+    }
+}
+
+pub fn set_feedrate(
+    mut commands: Commands,
+    mut gcode: ResMut<GCode>,
+    ui_res: Res<UiResource>,
+    s_query: Query<(&PickSelection, &Tag)>,
+    op: Res<SetFeedrate>,
+) {
+    commands.remove_resource::<SetFeedrate>();
+    let selection = get_selections(s_query);
+    let lines = expand_selection(&gcode.0, &selection, ui_res.selection_enum);
+    let changed = gcode.0.set_feedrate(&lines, op.0);
+    if changed > 0 {
+        let op = match op.0 {
+            FeedrateOp::Multiply(factor) => format!("by {}", factor),
+            FeedrateOp::Set(f) => format!("to {}", f),
+            FeedrateOp::Clamp(min, max) => format!("to {}-{}", min, max),
+        };
+        commands.insert_resource(EditName(format!(
+            "Set feedrate of {} moves {}",
+            changed, op
+        )));
+        commands.init_resource::<ForceRefresh>();
+    }
+}
+
+pub fn scale_flow(
+    mut commands: Commands,
+    mut gcode: ResMut<GCode>,
+    ui_res: Res<UiResource>,
+    s_query: Query<(&PickSelection, &Tag)>,
+    factor: Res<ScaleFlow>,
+) {
+    commands.remove_resource::<ScaleFlow>();
+    let selection = get_selections(s_query);
+    let lines = expand_selection(&gcode.0, &selection, ui_res.selection_enum);
+    let changed = gcode.0.scale_flow(&lines, factor.0);
+    if changed > 0 {
+        commands.insert_resource(EditName(format!(
+            "Scale flow of {} moves by {}",
+            changed, factor.0
+        )));
+        commands.init_resource::<ForceRefresh>();
+    }
+}
+
+pub fn apply_affine(
+    mut commands: Commands,
+    mut gcode: ResMut<GCode>,
+    ui_res: Res<UiResource>,
+    s_query: Query<(&PickSelection, &Tag)>,
+    affine: Res<ApplyAffine>,
+) {
+    commands.remove_resource::<ApplyAffine>();
+    let selection = get_selections(s_query);
+    let (vertices, matrix) = affine_matrix(&gcode.0, &selection, ui_res.selection_enum, affine.0);
+    let count = gcode.0.affine(&vertices, matrix);
+    if count > 0 {
+        let name = match affine.0 {
+            Affine::Mirror { .. } => "Mirror",
+            Affine::Scale(_) => "Scale",
+            Affine::Shear { .. } => "Shear",
+            Affine::Matrix(_) => "Transform",
+        };
+        commands.insert_resource(EditName(format!("{} {} vertices", name, count)));
+        commands.init_resource::<ForceRefresh>();
+    }
+}
+
+pub fn move_print(
+    mut commands: Commands,
+    mut gcode: ResMut<GCode>,
+    settings: Res<Settings>,
+    move_print: Res<MovePrint>,
+) {
+    commands.remove_resource::<MovePrint>();
+    let MovePrint { dx, dy, angle } = *move_print;
+    match gcode.0.move_print(dx, dy, angle, &settings.build_volume) {
+        Ok(0) => println!("no print moves found"),
+        Ok(count) => {
+            commands.insert_resource(EditName(format!(
+                "Move print by ({},{}) and turn it {} degrees, {} moves",
+                dx, dy, angle, count
+            )));
+            commands.init_resource::<ForceRefresh>();
+        }
+        Err(report) => commands.insert_resource(MovePrintWarning(report)),
+    }
+}
+
+fn read_height_map(
+    path: &str,
+    extent: (f32, f32, f32, f32),
+) -> Result<HeightMap, Box<dyn std::error::Error>> {
+    use bevy::render::{
+        render_asset::RenderAssetUsages,
+        render_resource::TextureFormat,
+        texture::{CompressedImageFormats, ImageSampler, ImageType},
+    };
+    let bytes = std::fs::read(path)?;
+    let extension = std::path::Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("png");
+    let image = Image::from_buffer(
+        &bytes,
+        ImageType::Extension(extension),
+        CompressedImageFormats::NONE,
+        false,
+        ImageSampler::Default,
+        RenderAssetUsages::default(),
+    )?
+    .convert(TextureFormat::R8Unorm)
+    .ok_or("could not read the image as greyscale")?;
+    let size = image.size();
+    Ok(HeightMap {
+        values: image.data.iter().map(|v| *v as f32 / 255.0).collect(),
+        width: size.x as usize,
+        height: size.y as usize,
+        extent,
+    })
+}
+
+pub fn deform_selection(
+    mut commands: Commands,
+    mut gcode: ResMut<GCode>,
+    ui_res: Res<UiResource>,
+    s_query: Query<(&PickSelection, &Tag)>,
+) {
+    commands.remove_resource::<DeformSelection>();
+    let deform = &ui_res.deform;
+    let surface = match deform.kind {
+        SurfaceKind::Sine => Surface::Sine {
+            amplitude: deform.amplitude,
+            wavelength: deform.wavelength,
+            angle: deform.angle,
+        },
+        SurfaceKind::HeightMap => match read_height_map(&deform.image, deform.extent) {
+            Ok(map) => Surface::HeightMap {
+                map,
+                amplitude: deform.amplitude,
+            },
+            Err(e) => {
+                println!("failed to read height map: {}", e);
+                return;
+            }
+        },
+        SurfaceKind::Expression => match Expr::parse(&deform.expression) {
+            Ok(expr) => Surface::Expression(expr),
+            Err(e) => {
+                println!("failed to parse expression: {}", e);
+                return;
+            }
+        },
+    };
+    let selection = get_selections(s_query);
+    let lines = expand_selection(&gcode.0, &selection, ui_res.selection_enum);
+    let report = gcode.0.deform(
+        &lines,
+        &surface,
+        deform.max_segment,
+        ui_res.collision_params.clearance,
+    );
+    if report.moved > 0 {
+        commands.insert_resource(EditName(format!("Deform {} vertices", report.moved)));
+        commands.init_resource::<ForceRefresh>();
+    }
+    commands.insert_resource(DeformResult(report));
+}
+
+// draws where the selection would end up while the transform is being set up
+pub fn preview_affine(
+    mut gizmos: Gizmos,
+    gcode: Res<GCode>,
+    ui_res: Res<UiResource>,
+    s_query: Query<(&PickSelection, &Tag)>,
+) {
+    // the preview only shows while the transform window is open
+    if !ui_res.affine_preview || !ui_res.windows.transform {
+        return;
+    }
+    let selection = get_selections(s_query);
+    let gcode = &gcode.0;
+    let (vertices, matrix) = affine_matrix(gcode, &selection, ui_res.selection_enum, ui_res.affine);
+    let point = |id: &Id| {
+        let to = gcode.vertices.get(id).unwrap().to;
+        let p = Vec3::new(to.x, to.y, to.z);
+        if vertices.contains(id) {
+            matrix.transform_point3(p)
+        } else {
+            p
+        }
+    };
+    for id in &vertices {
+        if let Some(prev) = gcode.vertices.get(id).unwrap().prev {
+            gizmos.line(point(&prev), point(id), Color::WHITE);
+        }
+    }
+}
+
+pub fn insert_pause(
+    mut commands: Commands,
+    mut gcode: ResMut<GCode>,
+    ui_res: Res<UiResource>,
+    layers: Res<InsertPause>,
+) {
+    commands.remove_resource::<InsertPause>();
+    let mut inserted = 0;
+    for z in &layers.0 {
+        match gcode.0.insert_pause(*z, &ui_res.pause) {
+            Ok(_) => inserted += 1,
+            Err(e) => println!("failed to insert pause at z {}: {}", z, e),
+        }
+    }
+    if inserted > 0 {
+        commands.insert_resource(EditName(format!(
+            "Insert {} at {} layers",
+            ui_res.pause.command, inserted
+        )));
+        commands.init_resource::<ForceRefresh>();
+    }
+}
+
+pub fn check_limits(mut commands: Commands, gcode: Res<GCode>, ui_res: Res<UiResource>) {
+    let report = gcode.0.check_limits(&ui_res.limits);
+    commands.insert_resource(LimitReport(report));
+    commands.init_resource::<ForceRefresh>();
+    commands.remove_resource::<CheckLimits>();
+}
+
+pub fn clamp_limits(mut commands: Commands, mut gcode: ResMut<GCode>, ui_res: Res<UiResource>) {
+    let count = gcode.0.clamp_to_limits(&ui_res.limits);
+    println!("clamped feedrate on {} moves", count);
+    commands.insert_resource(EditName(format!("Clamp feedrate on {} moves", count)));
+    commands.init_resource::<CheckLimits>();
+    commands.remove_resource::<ClampLimits>();
+}
+
+pub fn save(mut commands: Commands, gcode: Res<GCode>, settings: Res<Settings>, save: Res<Save>) {
+    commands.remove_resource::<Save>();
+    if !save.force {
+        let report = gcode.0.out_of_bounds(&settings.build_volume);
+        if !report.is_empty() {
+            commands.insert_resource(SaveWarning(report));
+            return;
+        }
+    }
+    let _ = gcode.0.write_to_file("./test_output.gcode");
+}
+
+pub fn export_svg(
+    mut commands: Commands,
+    gcode: Res<GCode>,
+    ui_res: Res<UiResource>,
+    export: Res<ExportSvg>,
+) {
+    commands.remove_resource::<ExportSvg>();
+    if export.all {
+        if let Ok(count) = gcode.0.write_layer_svgs("./svg", false) {
+            println!("wrote {} layers to ./svg", count);
+        }
+        return;
+    }
+    // the top layer being displayed
+    let Some(z) = gcode
+        .0
+        .layers()
+        .into_iter()
+        .rev()
+        .find(|z| *z <= ui_res.display_z_max.0)
+    else {
+        return;
+    };
+    let svg = gcode.0.layer_svg(z, false);
+    if std::fs::write(format!("./layer_z{:.2}.svg", z), svg).is_ok() {
+        println!("layer svg saved");
+    }
+}
+
+pub fn check_collisions(mut commands: Commands, gcode: Res<GCode>, ui_res: Res<UiResource>) {
+    let report = gcode.0.collisions(&ui_res.collision_params);
+    commands.insert_resource(CollisionReport(report));
+    commands.remove_resource::<CheckCollisions>();
+}
+
+pub fn add_z_hops(mut commands: Commands, mut gcode: ResMut<GCode>, params: Res<AddZHops>) {
+    commands.remove_resource::<AddZHops>();
+    let count = gcode.0.z_hop(&params.0);
+    if count > 0 {
+        commands.insert_resource(EditName(format!(
+            "Add {} z-hops of {}mm",
+            count, params.0.height
+        )));
+        commands.init_resource::<ForceRefresh>();
+    }
+}
+
+pub fn set_retractions(
+    mut commands: Commands,
+    mut gcode: ResMut<GCode>,
+    params: Res<SetRetractions>,
+) {
+    commands.remove_resource::<SetRetractions>();
+    let (added, removed) = gcode.0.set_retractions(&params.0);
+    if added + removed > 0 {
+        commands.insert_resource(EditName(format!(
+            "Add {} retractions and remove {}",
+            added, removed
+        )));
+        commands.init_resource::<ForceRefresh>();
+    }
+}
+
+pub fn add_wipes(mut commands: Commands, mut gcode: ResMut<GCode>, params: Res<AddWipes>) {
+    commands.remove_resource::<AddWipes>();
+    let count = gcode.0.add_wipes(&params.0);
+    if count > 0 {
+        commands.insert_resource(EditName(format!(
+            "Wipe {}mm before {} retractions",
+            params.0.distance, count
+        )));
+        commands.init_resource::<ForceRefresh>();
+    }
+}
+
+pub fn analyze_retractions(mut commands: Commands, gcode: Res<GCode>) {
+    commands.insert_resource(RetractionAnalysis(gcode.0.retraction_report()));
+    commands.remove_resource::<AnalyzeRetractions>();
+}
+
+pub fn preview_schedule(
+    mut commands: Commands,
+    gcode: Res<GCode>,
+    requested: Res<PreviewSchedule>,
+) {
+    commands.insert_resource(SchedulePreview(
+        gcode.0.schedule_preview(requested.0, &requested.1),
+    ));
+    commands.remove_resource::<PreviewSchedule>();
+}
+
+pub fn apply_schedule(
+    mut commands: Commands,
+    mut gcode: ResMut<GCode>,
+    schedule: Res<ApplySchedule>,
+) {
+    commands.remove_resource::<ApplySchedule>();
+    let ApplySchedule(param, schedule) = schedule.as_ref();
+    match gcode.0.apply_schedule(*param, schedule) {
+        Ok(inserted) => {
+            commands.insert_resource(EditName(format!(
+                "Schedule {} at {} layers",
+                param.name(),
+                inserted
+            )));
+            commands.insert_resource(SchedulePreview(gcode.0.schedule_preview(*param, schedule)));
+            commands.init_resource::<ForceRefresh>();
+        }
+        Err(e) => println!("failed to apply schedule: {}", e),
+    }
+}
+
+pub fn analyze_seams(mut commands: Commands, gcode: Res<GCode>) {
+    commands.insert_resource(SeamAnalysis(gcode.0.seam_stats()));
+    commands.remove_resource::<AnalyzeSeams>();
+}
+
+pub fn seams_to_selection(
+    mut commands: Commands,
+    mut gcode: ResMut<GCode>,
+    s_query: Query<(&PickSelection, &Tag)>,
+) {
+    let selection = get_selections(s_query);
+    let moved = gcode.0.set_seams(&selection);
+    println!("moved {} seams", moved);
+    commands.insert_resource(EditName(format!("Move {} seams to selection", moved)));
+    commands.init_resource::<ForceRefresh>();
+    commands.remove_resource::<SeamsToSelection>();
+}
+
+pub fn align_seams(mut commands: Commands, mut gcode: ResMut<GCode>, align: Res<AlignSeams>) {
+    let moved = gcode.0.align_seams(align.0);
+    println!("moved {} seams", moved);
+    commands.insert_resource(EditName(format!("Align {} seams", moved)));
+    commands.init_resource::<ForceRefresh>();
+    commands.remove_resource::<AlignSeams>();
+}
+
+pub fn classify_support(mut commands: Commands, mut gcode: ResMut<GCode>) {
+    gcode.0.classify_support();
+    commands.init_resource::<ForceRefresh>();
+    commands.remove_resource::<ClassifySupport>();
+}
+
+pub fn slow_unsupported(mut commands: Commands, mut gcode: ResMut<GCode>, ui_res: Res<UiResource>) {
+    gcode.0.classify_support();
+    gcode
+        .0
+        .slow_unsupported(ui_res.overhang_speed * 60.0, ui_res.overhang_fan);
+    commands.insert_resource(EditName(format!(
+        "Slow unsupported moves to {:.0}mm/s",
+        ui_res.overhang_speed
+    )));
+    commands.init_resource::<ForceRefresh>();
+    commands.remove_resource::<SlowUnsupported>();
+}
+
+pub fn select_ids(
+    mut commands: Commands,
+    mut s_query: Query<(&mut PickSelection, &Tag)>,
+    ids: Res<SelectIds>,
+) {
+    for (mut selection, tag) in s_query.iter_mut() {
+        selection.is_selected = ids.0.contains(&tag.id);
+    }
+    commands.remove_resource::<SelectIds>();
+}
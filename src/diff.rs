@@ -1,6 +1,6 @@
 use super::{
-    print_analyzer::{Instruction, Vertex},
-    GCode, Id, Resource, Tag,
+    print_analyzer::{Changes, Instruction, Parsed, Vertex},
+    ForceRefresh, GCode, Id, Resource, Tag,
 };
 use bevy::prelude::*;
 use bevy_mod_picking::selection::PickSelection;
@@ -41,96 +41,128 @@ impl SelectionLog {
     }
 }
 
-// #[derive(Debug, PartialEq)]
-// pub struct Parsed {
-// pub lines: Vec<Id>,
-// pub vertices: HashMap<Id, Vertex>,
-// pub instructions: HashMap<Id, Instruction>,
-// pub shapes: Vec<Shape>,
-// pub rel_xyz: bool,
-// pub rel_e: bool,
-// id_counter: Id,
-// }
-
-#[derive(Resource)]
+// the edits made to the gcode, each with only the parts of lines, vertices and instructions it
+// touched as they were before and after it
+#[derive(Default, Resource)]
 pub struct GCodeLog {
-    curr: GCode,
     log: Vec<GCodeDiff>,
     pub history_counter: u32,
     curr_counter: u32,
+    // named states, as the number of edits applied to reach them
+    checkpoints: Vec<(String, usize)>,
 }
 
+// describes the next recorded edit in the history
+#[derive(Resource)]
+pub struct EditName(pub String);
+
 impl GCodeLog {
-    fn init(gcode: Res<GCode>) -> Self {
-        Self {
-            curr: gcode.clone(),
-            log: Vec::new(),
-            history_counter: 0,
-            curr_counter: 0,
-        }
+    pub fn len(&self) -> usize {
+        self.log.len()
     }
-
-    fn diff(&self, next: &GCode) -> GCodeDiff {
-        let line_diff = vec_diff(&self.curr.0.lines, &next.0.lines);
-        let vertex_diff = map_diff(&self.curr.0.vertices, &next.0.vertices);
-        let instruction_diff = map_diff(&self.curr.0.instructions, &next.0.instructions);
-        GCodeDiff {
-            add: line_diff.0,
-            line_diff: line_diff.1,
-            vertex_diff: vertex_diff.1,
-            instruction_diff: instruction_diff.1,
-        }
+    // how many edits the displayed gcode has applied
+    pub fn position(&self) -> usize {
+        self.log.len() - self.curr_counter as usize
+    }
+    pub fn descriptions(&self) -> impl Iterator<Item = &str> {
+        self.log.iter().map(|d| d.description.as_str())
+    }
+    // sets the state undo_redo_gcode should move to
+    pub fn jump(&mut self, position: usize) {
+        self.history_counter = (self.log.len() - position.min(self.log.len())) as u32;
+    }
+    pub fn checkpoints(&self) -> &[(String, usize)] {
+        &self.checkpoints
+    }
+    pub fn add_checkpoint(&mut self, name: String) {
+        let position = self.position();
+        self.checkpoints.push((name, position));
+    }
+    pub fn remove_checkpoint(&mut self, i: usize) {
+        self.checkpoints.remove(i);
     }
 }
 
+// the change made by one edit, applied forward to redo and backward to undo
+#[derive(Clone)]
 pub struct GCodeDiff {
-    add: bool,
-    line_diff: HashSet<(usize, Id)>,
-    vertex_diff: HashMap<Id, Vertex>,
-    instruction_diff: HashMap<Id, Instruction>,
+    description: String,
+    // lines[start..start + old_lines.len()] became new_lines
+    start: usize,
+    old_lines: Vec<Id>,
+    new_lines: Vec<Id>,
+    // (id, before, after) where None means the entry didn't exist
+    vertices: Vec<(Id, Option<Vertex>, Option<Vertex>)>,
+    instructions: Vec<(Id, Option<Instruction>, Option<Instruction>)>,
 }
 
 impl GCodeDiff {
-    fn apply(&self, gcode: &mut GCode) {
-        if self.add {
-            for (i, id) in self.line_diff.iter() {
-                gcode.0.lines.insert(*i as usize, *id);
-            }
-            gcode.0.vertices.extend(self.vertex_diff.clone());
-            gcode.0.instructions.extend(self.instruction_diff.clone())
-        } else {
-            for (i, id) in self.line_diff.iter() {
-                gcode.0.lines.remove(*i);
-            }
+    // the edit from the old values the gcode kept to what it is now
+    fn build(changes: Changes, gcode: &Parsed) -> GCodeDiff {
+        let (start, old_lines, new_lines) =
+            changes.lines.map_or((0, Vec::new(), Vec::new()), |old| {
+                vec_diff(&old, &gcode.lines)
+            });
+        GCodeDiff {
+            description: String::new(),
+            start,
+            old_lines,
+            new_lines,
+            vertices: entry_diff(changes.vertices, &gcode.vertices),
+            instructions: entry_diff(changes.instructions, &gcode.instructions),
         }
     }
+    fn is_empty(&self) -> bool {
+        self.old_lines == self.new_lines && self.vertices.is_empty() && self.instructions.is_empty()
+    }
+    fn apply(&self, gcode: &mut Parsed, forward: bool) {
+        let (from, to) = if forward {
+            (&self.old_lines, &self.new_lines)
+        } else {
+            (&self.new_lines, &self.old_lines)
+        };
+        gcode
+            .lines
+            .splice(self.start..self.start + from.len(), to.iter().copied());
+        set_entries(&mut gcode.vertices, &self.vertices, forward);
+        set_entries(&mut gcode.instructions, &self.instructions, forward);
+    }
 }
 
-fn vec_diff<T>(curr: &Vec<T>, next: &Vec<T>) -> (bool, HashSet<(usize, T)>)
+fn set_entries<S, T>(map: &mut HashMap<S, T>, entries: &[(S, Option<T>, Option<T>)], forward: bool)
 where
-    T: Copy + Eq + std::hash::Hash,
+    S: Copy + Eq + std::hash::Hash,
+    T: Clone,
 {
-    let mut out = HashSet::new();
-    let mut i = 0;
-    let add = curr.len() < next.len(); // add (true) if curr < len
-    if add {
-        for (j, elem) in next.iter().enumerate() {
-            if i < curr.len() && curr[i] == next[j] {
-                i += 1;
-            } else {
-                assert!(out.insert((j, *elem))); // make sure the inserted value is unique
+    for (key, before, after) in entries {
+        match if forward { after } else { before } {
+            Some(value) => {
+                map.insert(*key, value.clone());
             }
-        }
-    } else {
-        for (j, elem) in curr.iter().enumerate() {
-            if i < next.len() && next[i] == curr[j] {
-                i += 1;
-            } else {
-                assert!(out.insert((j, *elem))); // make sure the inserted value is unique
+            None => {
+                map.remove(key);
             }
         }
     }
-    (add, out)
+}
+
+// the span between the common prefix and suffix of the two vecs, as (start, in curr, in next)
+fn vec_diff<T>(curr: &[T], next: &[T]) -> (usize, Vec<T>, Vec<T>)
+where
+    T: Copy + PartialEq,
+{
+    let prefix = curr.iter().zip(next).take_while(|(a, b)| a == b).count();
+    let suffix = curr[prefix..]
+        .iter()
+        .rev()
+        .zip(next[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    (
+        prefix,
+        curr[prefix..curr.len() - suffix].to_vec(),
+        next[prefix..next.len() - suffix].to_vec(),
+    )
 }
 
 fn set_diff<T>(curr: &HashSet<T>, next: &HashSet<T>) -> (bool, HashSet<T>)
@@ -147,37 +179,20 @@ where
     }
 }
 
-fn map_diff<S, T>(curr: &HashMap<S, T>, next: &HashMap<S, T>) -> (bool, HashMap<S, T>)
+// the touched keys that ended up different, as (key, before, after)
+fn entry_diff<S, T>(
+    before: HashMap<S, Option<T>>,
+    now: &HashMap<S, T>,
+) -> Vec<(S, Option<T>, Option<T>)>
 where
-    S: Copy + PartialEq + Eq + core::hash::Hash,
-    T: Clone,
+    S: Copy + Eq + std::hash::Hash,
+    T: Clone + PartialEq,
 {
-    let add = curr.len() < next.len();
-    let (curr_keys, next_keys) = (
-        curr.keys().copied().collect::<HashSet<_>>(),
-        next.keys().copied().collect::<HashSet<_>>(),
-    );
-    let diff_keys = {
-        if add {
-            curr_keys.difference(&next_keys)
-        } else {
-            next_keys.difference(&curr_keys)
-        }
-    }
-    .collect::<HashSet<&S>>();
-    let mut diff: HashMap<S, T> = HashMap::new();
-    if add {
-        for key in diff_keys.iter() {
-            let value = next.get(*key).unwrap();
-            diff.insert(**key, value.clone());
-        }
-    } else {
-        for key in diff_keys.iter() {
-            let value = curr.get(*key).unwrap();
-            diff.insert(**key, value.clone());
-        }
-    }
-    (add, diff)
+    before
+        .into_iter()
+        .map(|(key, before)| (key, before, now.get(&key).cloned()))
+        .filter(|(_, before, after)| before != after)
+        .collect()
 }
 
 #[derive(Resource, Default)]
@@ -212,16 +227,63 @@ pub fn update_selection_log(
     commands.init_resource::<SetSelections>()
 }
 
+#[derive(Resource, Default)]
+pub struct UndoRedoGCode;
+
+// records whatever the gcode kept as changed since the last recorded edit
 pub fn update_gcode_log(
     mut commands: Commands,
     mut gcode: ResMut<GCode>,
     mut log: ResMut<GCodeLog>,
+    name: Option<Res<EditName>>,
 ) {
-    let diff = log.diff(&gcode);
-    diff.apply(&mut gcode);
+    commands.remove_resource::<EditName>();
+    let changes = gcode.0.take_changes();
+    let mut diff = GCodeDiff::build(changes, &gcode.0);
+    if diff.is_empty() {
+        return;
+    }
+    diff.description = name.map_or(String::from("Edit"), |n| n.0.clone());
+    // a new edit after undoing drops the undone edits and the checkpoints on them
+    if log.curr_counter > 0 {
+        let len = log.position();
+        log.log.truncate(len);
+        log.checkpoints.retain(|(_, position)| *position <= len);
+        log.history_counter = 0;
+        log.curr_counter = 0;
+    }
     log.log.push(diff);
 }
 
+pub fn undo_redo_gcode(
+    mut commands: Commands,
+    mut gcode: ResMut<GCode>,
+    mut log: ResMut<GCodeLog>,
+) {
+    let GCodeLog {
+        log: diffs,
+        history_counter,
+        curr_counter,
+        ..
+    } = &mut *log;
+    let len = diffs.len();
+    // ctrl+z
+    while *curr_counter < *history_counter {
+        let diff = &diffs[len - *curr_counter as usize - 1];
+        diff.apply(&mut gcode.0, false);
+        *curr_counter += 1;
+    }
+    // ctrl+shift+z
+    while *curr_counter > *history_counter {
+        let diff = &diffs[len - *curr_counter as usize];
+        diff.apply(&mut gcode.0, true);
+        *curr_counter -= 1;
+    }
+    gcode.0.assign_shapes();
+    commands.init_resource::<ForceRefresh>();
+    commands.remove_resource::<UndoRedoGCode>();
+}
+
 pub fn undo_redo_selections(
     mut commands: Commands,
     mut s_query: Query<(&mut PickSelection, &Tag)>,
@@ -252,3 +314,47 @@ pub fn undo_redo_selections(
     }
     commands.remove_resource::<SetSelections>()
 }
+
+#[test]
+fn gcode_log_test() {
+    let gcode = "G28\nG1 X10 Y10 Z0.2 F1200\nG1 X20 E1\nM117 hi\nG1 Y20 E1\nG1 X10 E1\nG1 Y10 E1";
+    let mut gcode = crate::print_analyzer::read(gcode, true).expect("failed to parse");
+    let original = gcode.clone();
+    let mut log = GCodeLog::default();
+    let mut states = vec![gcode.clone()];
+    let record = |gcode: &mut Parsed, log: &mut GCodeLog| {
+        let diff = GCodeDiff::build(gcode.take_changes(), gcode);
+        assert!(!diff.is_empty());
+        log.log.push(diff);
+    };
+    // a move, a delete and a subdivide, recorded one after the other
+    gcode.translate(&gcode.lines[2].clone(), 1.0, 1.0, 0.0);
+    record(&mut gcode, &mut log);
+    states.push(gcode.clone());
+    gcode.merge_delete(&mut HashSet::from([gcode.lines[5]]));
+    record(&mut gcode, &mut log);
+    // only the deleted move and the ones either side of it are kept, not the whole file
+    assert_eq!(log.log[1].vertices.len(), 3);
+    states.push(gcode.clone());
+    gcode.subdivide_vertices(HashSet::from([gcode.lines[2]]), 3);
+    record(&mut gcode, &mut log);
+    states.push(gcode.clone());
+    assert!(GCodeDiff::build(gcode.take_changes(), &gcode).is_empty());
+    let mut gcode = states.last().unwrap().clone();
+    for (diff, state) in log.log.iter().rev().zip(states.iter().rev().skip(1)) {
+        diff.apply(&mut gcode, false);
+        assert_eq!(gcode.lines, state.lines);
+        assert_eq!(gcode.vertices, state.vertices);
+        assert_eq!(gcode.instructions, state.instructions);
+    }
+    assert_eq!(gcode.vertices, original.vertices);
+    for diff in &log.log {
+        diff.apply(&mut gcode, true);
+    }
+    assert_eq!(gcode.lines, states[3].lines);
+    assert_eq!(gcode.vertices, states[3].vertices);
+    log.add_checkpoint(String::from("subdivided"));
+    assert_eq!(log.checkpoints(), &[(String::from("subdivided"), 3)]);
+    log.jump(1);
+    assert_eq!(log.history_counter, 2);
+}
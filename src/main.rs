@@ -11,7 +11,10 @@ use bevy::prelude::*;
 use bevy_egui::{EguiContext, EguiPlugin};
 use bevy_mod_picking::prelude::*;
 use callbacks::*;
-use diff::{undo_redo_selections, update_selection_log, SelectionLog, SetSelections};
+use diff::{
+    undo_redo_gcode, undo_redo_selections, update_gcode_log, update_selection_log, EditName,
+    GCodeLog, SelectionLog, SetSelections, UndoRedoGCode,
+};
 use pan_orbit::{pan_orbit_camera, PanOrbitCamera};
 use picking_core::PickingPluginsSettings;
 use print_analyzer::{Id, Parsed};
@@ -56,7 +59,9 @@ fn setup(
             filename = name;
         }
     }
-    let gcode = print_analyzer::read(filename, false).expect("failed to read");
+    let settings = read_settings();
+    let mut gcode = print_analyzer::read(filename, false).expect("failed to read");
+    gcode.filament_diameter = settings.filament_diameter;
     commands.insert_resource(AmbientLight {
         color: Color::WHITE,
         brightness: 255.0,
@@ -70,23 +75,22 @@ fn setup(
             ..Default::default()
         },
     ));
-    let (w, l, _h) = (300.0, 300.0, 300.0);
+    let (bed, transform) = bed_mesh(&settings.build_volume);
     let _ = commands.spawn(PbrBundle {
-        mesh: meshes.add(Cuboid::new(w, l, -0.1)),
+        mesh: meshes.add(bed),
         material: materials.add(StandardMaterial {
             base_color: Color::WHITE,
             emissive: Color::WHITE,
+            cull_mode: None,
             ..Default::default()
         }),
-        transform: Transform {
-            translation: Vec3::new(w / 2.0, l / 2.0, 0.0),
-            ..Default::default()
-        },
+        transform,
         ..Default::default()
     });
 
-    commands.insert_resource(read_settings());
+    commands.insert_resource(settings);
     commands.insert_resource(VertexCounter::build(&gcode));
+    commands.init_resource::<GCodeLog>();
     commands.insert_resource(GCode(gcode));
     commands.init_resource::<ForceRefresh>();
     commands.init_resource::<UiResource>();
@@ -95,6 +99,22 @@ fn setup(
     commands.init_resource::<SelectionLog>();
 }
 fn main() {
+    // `g-wiz <file> --retraction-json` prints retraction statistics for CI instead of opening the editor
+    let args: Vec<String> = env::args().collect();
+    if args.len() > 2 && args[2] == "--retraction-json" {
+        let gcode = print_analyzer::read(&args[1], false).expect("failed to read");
+        println!("{}", gcode.retraction_report().to_json());
+        return;
+    }
+    // `g-wiz <file> --svg <dir>` writes every layer as an svg
+    if args.len() > 3 && args[2] == "--svg" {
+        let gcode = print_analyzer::read(&args[1], false).expect("failed to read");
+        let count = gcode
+            .write_layer_svgs(&args[3], false)
+            .expect("failed to write svg");
+        println!("wrote {} layers to {}", count, args[3]);
+        return;
+    }
     App::new()
         .add_plugins((
             DefaultPlugins.set(WindowPlugin {
@@ -131,14 +151,78 @@ fn main() {
                 merge_delete.run_if(resource_exists::<MergeDelete>),
                 hole_delete.run_if(resource_exists::<HoleDelete>),
                 subdivide_selection.run_if(resource_exists::<SubdivideSelection>),
+                limits_window,
+                clamp_limits.run_if(resource_exists::<ClampLimits>),
+                check_limits.run_if(resource_exists::<CheckLimits>),
+                save_warning_window.run_if(resource_exists::<SaveWarning>),
+                save.run_if(resource_exists::<Save>),
+                collisions_window,
+                check_collisions.run_if(resource_exists::<CheckCollisions>),
+                select_ids.run_if(resource_exists::<SelectIds>),
             )
                 .chain(),
         )
+        .add_systems(
+            Update,
+            (
+                retraction_window,
+                analyze_retractions.run_if(resource_exists::<AnalyzeRetractions>),
+                classify_support.run_if(resource_exists::<ClassifySupport>),
+                slow_unsupported.run_if(resource_exists::<SlowUnsupported>),
+                seams_window,
+                analyze_seams.run_if(resource_exists::<AnalyzeSeams>),
+                seams_to_selection.run_if(resource_exists::<SeamsToSelection>),
+                align_seams.run_if(resource_exists::<AlignSeams>),
+                export_svg.run_if(resource_exists::<ExportSvg>),
+                history_window,
+                insert_gcode.run_if(resource_exists::<InsertGCode>),
+                pause_window,
+                insert_pause.run_if(resource_exists::<InsertPause>),
+                schedule_window,
+                preview_schedule.run_if(resource_exists::<PreviewSchedule>),
+                apply_schedule.run_if(resource_exists::<ApplySchedule>),
+                set_feedrate.run_if(resource_exists::<SetFeedrate>),
+                scale_flow.run_if(resource_exists::<ScaleFlow>),
+            )
+                .chain()
+                .after(ui_system),
+        )
+        .add_systems(
+            Update,
+            (
+                affine_window,
+                apply_affine.run_if(resource_exists::<ApplyAffine>),
+                preview_affine,
+                move_print.run_if(resource_exists::<MovePrint>),
+                move_print_warning_window.run_if(resource_exists::<MovePrintWarning>),
+                deform_window,
+                deform_selection.run_if(resource_exists::<DeformSelection>),
+                add_z_hops.run_if(resource_exists::<AddZHops>),
+                set_retractions.run_if(resource_exists::<SetRetractions>),
+                add_wipes.run_if(resource_exists::<AddWipes>),
+            )
+                .chain()
+                .after(ui_system),
+        )
         .add_systems(
             Update,
             pan_orbit_camera.run_if(resource_exists::<EnablePanOrbit>),
         )
+        .add_systems(
+            Update,
+            undo_redo_gcode
+                .run_if(resource_exists::<UndoRedoGCode>)
+                .after(key_system)
+                .before(ui_system),
+        )
         .add_systems(Update, render.run_if(resource_exists::<ForceRefresh>))
-        .add_systems(PostUpdate, reset_ui_hover)
+        .add_systems(
+            PostUpdate,
+            (
+                reset_ui_hover,
+                update_gcode_log
+                    .run_if(resource_changed::<GCode>.or_else(resource_exists::<EditName>)),
+            ),
+        )
         .run();
 }
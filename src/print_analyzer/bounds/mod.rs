@@ -0,0 +1,164 @@
+use super::{Id, Label, Parsed};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Bed {
+    Rectangle {
+        x_min: f32,
+        y_min: f32,
+        x_max: f32,
+        y_max: f32,
+    },
+    // round delta beds
+    Circle {
+        x: f32,
+        y: f32,
+        radius: f32,
+    },
+    // any simple polygon, points in order around the outline
+    Polygon(Vec<(f32, f32)>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BuildVolume {
+    pub bed: Bed,
+    pub height: f32,
+}
+
+// even-odd ray casting test
+pub fn point_in_polygon(points: &[(f32, f32)], x: f32, y: f32) -> bool {
+    let mut inside = false;
+    let mut j = points.len().wrapping_sub(1);
+    for i in 0..points.len() {
+        let (xi, yi) = points[i];
+        let (xj, yj) = points[j];
+        if (yi > y) != (yj > y) && x < (xj - xi) * (y - yi) / (yj - yi) + xi {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
+
+impl BuildVolume {
+    pub fn contains_xy(&self, x: f32, y: f32) -> bool {
+        let tol = 1e-3;
+        match &self.bed {
+            Bed::Rectangle {
+                x_min,
+                y_min,
+                x_max,
+                y_max,
+            } => x >= x_min - tol && x <= x_max + tol && y >= y_min - tol && y <= y_max + tol,
+            Bed::Circle {
+                x: cx,
+                y: cy,
+                radius,
+            } => ((x - cx).powi(2) + (y - cy).powi(2)).sqrt() <= radius + tol,
+            Bed::Polygon(points) => point_in_polygon(points, x, y),
+        }
+    }
+    // (x_min, y_min, x_max, y_max) of the bed
+    pub fn extent(&self) -> (f32, f32, f32, f32) {
+        match &self.bed {
+            Bed::Rectangle {
+                x_min,
+                y_min,
+                x_max,
+                y_max,
+            } => (*x_min, *y_min, *x_max, *y_max),
+            Bed::Circle { x, y, radius } => (x - radius, y - radius, x + radius, y + radius),
+            Bed::Polygon(points) => points.iter().fold(
+                (
+                    f32::INFINITY,
+                    f32::INFINITY,
+                    f32::NEG_INFINITY,
+                    f32::NEG_INFINITY,
+                ),
+                |(x0, y0, x1, y1), (x, y)| (x0.min(*x), y0.min(*y), x1.max(*x), y1.max(*y)),
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BoundsKind {
+    OffBed,
+    BelowBed,
+    AboveTop,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OutOfBounds {
+    pub id: Id,
+    // 1-based position in the parsed lines
+    pub line: usize,
+    pub kind: BoundsKind,
+}
+
+impl Parsed {
+    pub fn out_of_bounds(&self, volume: &BuildVolume) -> Vec<OutOfBounds> {
+        let mut out = Vec::new();
+        for (i, line) in self.lines.iter().enumerate() {
+            let Some(v) = self.vertices.get(line) else {
+                continue;
+            };
+            if v.label == Label::Home {
+                continue;
+            }
+            let kind = if v.to.z < 0.0 {
+                BoundsKind::BelowBed
+            } else if v.to.z > volume.height {
+                BoundsKind::AboveTop
+            } else if !volume.contains_xy(v.to.x, v.to.y) {
+                BoundsKind::OffBed
+            } else {
+                continue;
+            };
+            out.push(OutOfBounds {
+                id: v.id,
+                line: i + 1,
+                kind,
+            });
+        }
+        out
+    }
+}
+
+#[test]
+fn bounds_test() {
+    let gcode = "G28\nG1 X10 Y10 Z0.2\nG1 X150 Y10 E1\nG1 X150 Y10 Z-0.1\nG1 X10 Y250 Z0.2";
+    let gcode = super::read(gcode, true).expect("failed to parse");
+    let rect = BuildVolume {
+        bed: Bed::Rectangle {
+            x_min: 0.0,
+            y_min: 0.0,
+            x_max: 200.0,
+            y_max: 200.0,
+        },
+        height: 200.0,
+    };
+    let report = gcode.out_of_bounds(&rect);
+    let kinds = report.iter().map(|r| (r.line, r.kind)).collect::<Vec<_>>();
+    assert_eq!(
+        kinds,
+        vec![(4, BoundsKind::BelowBed), (5, BoundsKind::OffBed)]
+    );
+
+    let round = BuildVolume {
+        bed: Bed::Circle {
+            x: 100.0,
+            y: 100.0,
+            radius: 100.0,
+        },
+        height: 200.0,
+    };
+    assert!(round.contains_xy(100.0, 199.0));
+    assert!(!round.contains_xy(10.0, 10.0));
+
+    let triangle = BuildVolume {
+        bed: Bed::Polygon(vec![(0.0, 0.0), (200.0, 0.0), (0.0, 200.0)]),
+        height: 200.0,
+    };
+    assert!(triangle.contains_xy(20.0, 20.0));
+    assert!(!triangle.contains_xy(150.0, 150.0));
+}
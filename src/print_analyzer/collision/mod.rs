@@ -0,0 +1,291 @@
+use super::{Id, Label, Parsed};
+use std::collections::HashMap;
+
+type Point = (f32, f32);
+
+// distance trimmed off both ends of a travel before checking what it crosses
+const TRAVEL_TRIM: f32 = 0.5;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CollisionParams {
+    // how far above deposited material the nozzle has to be to not hit it
+    pub clearance: f32,
+    // suggest a z-hop or a detour for each flagged travel
+    pub suggest: bool,
+    pub z_hop: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CollisionKind {
+    // a travel dragging the nozzle across this many deposited segments
+    TravelOverPart(usize),
+    // a move going this far below deposited material
+    BelowPrinted(f32),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Fix {
+    ZHop(f32),
+    // xy waypoints to travel through instead of going straight
+    Reroute(Vec<Point>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Collision {
+    pub id: Id,
+    // 1-based position in the parsed lines
+    pub line: usize,
+    pub kind: CollisionKind,
+    pub fix: Option<Fix>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Deposit {
+    a: Point,
+    b: Point,
+    half_width: f32,
+    top: f32,
+}
+
+fn dist_to_segment(p: Point, a: Point, b: Point) -> f32 {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let len = dx * dx + dy * dy;
+    let t = if len > 0.0 {
+        (((p.0 - a.0) * dx + (p.1 - a.1) * dy) / len).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    ((p.0 - a.0 - t * dx).powi(2) + (p.1 - a.1 - t * dy).powi(2)).sqrt()
+}
+
+fn path_length(path: &[Point]) -> f32 {
+    path.windows(2)
+        .map(|leg| ((leg[1].0 - leg[0].0).powi(2) + (leg[1].1 - leg[0].1).powi(2)).sqrt())
+        .sum()
+}
+
+fn cross(o: Point, a: Point, b: Point) -> f32 {
+    (a.0 - o.0) * (b.1 - o.1) - (a.1 - o.1) * (b.0 - o.0)
+}
+
+fn segments_dist(a: Point, b: Point, c: Point, d: Point) -> f32 {
+    let (d1, d2) = (cross(a, b, c), cross(a, b, d));
+    let (d3, d4) = (cross(c, d, a), cross(c, d, b));
+    if d1 * d2 < 0.0 && d3 * d4 < 0.0 {
+        return 0.0;
+    }
+    dist_to_segment(a, c, d)
+        .min(dist_to_segment(b, c, d))
+        .min(dist_to_segment(c, a, b))
+        .min(dist_to_segment(d, a, b))
+}
+
+// everything deposited so far, bucketed on a grid so lookups stay local
+pub struct PrintedMap {
+    cell: f32,
+    cells: HashMap<(i32, i32), Vec<Deposit>>,
+}
+
+impl PrintedMap {
+    pub fn new(cell: f32) -> PrintedMap {
+        PrintedMap {
+            cell,
+            cells: HashMap::new(),
+        }
+    }
+    fn key(&self, p: Point) -> (i32, i32) {
+        (
+            (p.0 / self.cell).floor() as i32,
+            (p.1 / self.cell).floor() as i32,
+        )
+    }
+    fn cells_around(&self, a: Point, b: Point, margin: f32) -> Vec<(i32, i32)> {
+        let lo = self.key((a.0.min(b.0) - margin, a.1.min(b.1) - margin));
+        let hi = self.key((a.0.max(b.0) + margin, a.1.max(b.1) + margin));
+        let mut out = Vec::new();
+        for i in lo.0..=hi.0 {
+            for j in lo.1..=hi.1 {
+                out.push((i, j));
+            }
+        }
+        out
+    }
+    pub fn add(&mut self, a: Point, b: Point, half_width: f32, top: f32) {
+        let deposit = Deposit {
+            a,
+            b,
+            half_width,
+            top,
+        };
+        for key in self.cells_around(a, b, half_width) {
+            self.cells.entry(key).or_default().push(deposit);
+        }
+    }
+    fn deposits_near(&self, a: Point, b: Point) -> impl Iterator<Item = &Deposit> {
+        self.cells_around(a, b, self.cell)
+            .into_iter()
+            .filter_map(|key| self.cells.get(&key))
+            .flatten()
+            .filter(move |d| segments_dist(a, b, d.a, d.b) < d.half_width)
+    }
+    // number of deposits the nozzle at height z would drag through going from a to b
+    pub fn crossings(&self, a: Point, b: Point, z: f32, clearance: f32) -> usize {
+        let mut seen = Vec::new();
+        for d in self.deposits_near(a, b) {
+            // deposits are stored once per cell they touch
+            let key = (
+                d.a.0.to_bits(),
+                d.a.1.to_bits(),
+                d.b.0.to_bits(),
+                d.b.1.to_bits(),
+            );
+            if d.top + clearance > z && !seen.contains(&key) {
+                seen.push(key);
+            }
+        }
+        seen.len()
+    }
+    // fraction of a bead of the given half width centered at p that isn't resting on anything
+    pub fn unsupported(&self, p: Point, half_width: f32) -> f32 {
+        let mut out: f32 = 1.0;
+        for key in self.cells_around(p, p, half_width + self.cell) {
+            for d in self.cells.get(&key).into_iter().flatten() {
+                let hanging = dist_to_segment(p, d.a, d.b) + half_width - d.half_width;
+                out = out.min((hanging / (2.0 * half_width)).clamp(0.0, 1.0));
+            }
+        }
+        out
+    }
+    // the highest deposited material under the path from a to b
+    pub fn height_under(&self, a: Point, b: Point) -> f32 {
+        self.deposits_near(a, b)
+            .map(|d| d.top)
+            .fold(f32::NEG_INFINITY, f32::max)
+    }
+    // a detour around the crossed material, going through one or two corners of its bounding box
+    fn reroute(&self, a: Point, b: Point, z: f32, clearance: f32) -> Option<Vec<Point>> {
+        let (mut x0, mut y0, mut x1, mut y1) = (
+            f32::INFINITY,
+            f32::INFINITY,
+            f32::NEG_INFINITY,
+            f32::NEG_INFINITY,
+        );
+        let mut margin: f32 = 0.0;
+        for d in self.deposits_near(a, b).filter(|d| d.top + clearance > z) {
+            x0 = x0.min(d.a.0.min(d.b.0));
+            y0 = y0.min(d.a.1.min(d.b.1));
+            x1 = x1.max(d.a.0.max(d.b.0));
+            y1 = y1.max(d.a.1.max(d.b.1));
+            margin = margin.max(d.half_width * 2.0);
+        }
+        if !x0.is_finite() {
+            return None;
+        }
+        let margin = margin + 1.0;
+        let corners = [
+            (x0 - margin, y0 - margin),
+            (x1 + margin, y0 - margin),
+            (x1 + margin, y1 + margin),
+            (x0 - margin, y1 + margin),
+        ];
+        let clear = |path: &[Point]| {
+            path.windows(2)
+                .all(|leg| self.crossings(leg[0], leg[1], z, clearance) == 0)
+        };
+        let mut candidates = Vec::new();
+        for (i, c) in corners.iter().enumerate() {
+            candidates.push(vec![a, *c, b]);
+            candidates.push(vec![a, *c, corners[(i + 1) % 4], b]);
+            candidates.push(vec![a, *c, corners[(i + 3) % 4], b]);
+        }
+        candidates
+            .into_iter()
+            .filter(|path| clear(path))
+            .min_by(|p, q| path_length(p).partial_cmp(&path_length(q)).unwrap())
+            .map(|path| path[1..path.len() - 1].to_vec())
+    }
+}
+
+impl Parsed {
+    pub fn collisions(&self, params: &CollisionParams) -> Vec<Collision> {
+        let beads = self.beads();
+        let mut printed = PrintedMap::new(2.0);
+        let mut out = Vec::new();
+        for (i, line) in self.lines.iter().enumerate() {
+            let Some(v) = self.vertices.get(line) else {
+                continue;
+            };
+            if v.prev.is_none() {
+                continue;
+            }
+            let from = v.get_from(self);
+            let (a, b) = ((from.x, from.y), (v.to.x, v.to.y));
+            let mut report = |kind, fix| {
+                out.push(Collision {
+                    id: v.id,
+                    line: i + 1,
+                    kind,
+                    fix,
+                })
+            };
+            // z at the lower end of the move, which is where the nozzle gets closest
+            let z = from.z.min(v.to.z);
+            let height = printed.height_under(a, b);
+            if height - z > 1e-3 {
+                report(CollisionKind::BelowPrinted(height - z), None);
+            } else if v.label == Label::TravelMove {
+                // travels start and end on the material they connect, so don't count that
+                let direct = from.dist(&v.to);
+                let trim = TRAVEL_TRIM / direct;
+                let crossings = if trim < 0.5 {
+                    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+                    let a = (a.0 + dx * trim, a.1 + dy * trim);
+                    let b = (b.0 - dx * trim, b.1 - dy * trim);
+                    printed.crossings(a, b, z, params.clearance)
+                } else {
+                    0
+                };
+                if crossings > 0 {
+                    let fix = if !params.suggest {
+                        None
+                    } else if let Some(path) = printed
+                        .reroute(a, b, z, params.clearance)
+                        // only worth it if it's not much longer than hopping over
+                        .filter(|path| {
+                            let full = [&[a], path.as_slice(), &[b]].concat();
+                            path_length(&full) < direct * 2.0
+                        })
+                    {
+                        Some(Fix::Reroute(path))
+                    } else {
+                        Some(Fix::ZHop(params.z_hop))
+                    };
+                    report(CollisionKind::TravelOverPart(crossings), fix);
+                }
+            }
+            if let Some(bead) = beads.get(line) {
+                printed.add(a, b, bead.width / 2.0, v.to.z);
+            }
+        }
+        out
+    }
+}
+
+#[test]
+fn collision_test() {
+    // a square, then a travel straight across it, then a hop over it
+    let gcode = "G28\nG1 X20 Y20 Z0.2 F3000\nG1 X40 E1\nG1 Y40 E1\nG1 X20 E1\nG1 Y20 E1\nG1 X10 Y30\nG1 X50\nG1 Z0.6\nG1 X10\nG1 X30 Z0.1";
+    let gcode = super::read(gcode, true).expect("failed to parse");
+    let params = CollisionParams {
+        clearance: 0.1,
+        suggest: true,
+        z_hop: 0.4,
+    };
+    let report = gcode.collisions(&params);
+    assert_eq!(report.len(), 2);
+    assert_eq!(report[0].line, 8);
+    assert_eq!(report[0].kind, CollisionKind::TravelOverPart(2));
+    assert!(matches!(report[0].fix, Some(Fix::Reroute(_))));
+    assert_eq!(report[1].line, 11);
+    assert!(matches!(report[1].kind, CollisionKind::BelowPrinted(d) if (d - 0.1).abs() < 1e-3));
+}
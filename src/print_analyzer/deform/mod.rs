@@ -0,0 +1,328 @@
+use super::{
+    collision::{Collision, CollisionKind, CollisionParams},
+    edit::Feature,
+    Id, Label, Parsed,
+};
+use std::{collections::HashSet, error::Error, f32::consts::PI};
+
+// a formula of x and y, e.g. "0.5 * sin(x / 5) * cos(y / 5)"
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Number(f32),
+    X,
+    Y,
+    Neg(Box<Expr>),
+    // the operator is one of + - * / ^
+    Binary(char, Box<Expr>, Box<Expr>),
+    Call(String, Box<Expr>),
+}
+
+// recursive descent over the characters, lowest precedence first
+struct ExprParser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl ExprParser<'_> {
+    fn peek(&mut self) -> Option<char> {
+        while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+        self.chars.peek().copied()
+    }
+    fn sum(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let mut out = self.product()?;
+        while let Some(op @ ('+' | '-')) = self.peek() {
+            self.chars.next();
+            out = Expr::Binary(op, Box::new(out), Box::new(self.product()?));
+        }
+        Ok(out)
+    }
+    fn product(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let mut out = self.unary()?;
+        while let Some(op @ ('*' | '/')) = self.peek() {
+            self.chars.next();
+            out = Expr::Binary(op, Box::new(out), Box::new(self.unary()?));
+        }
+        Ok(out)
+    }
+    fn unary(&mut self) -> Result<Expr, Box<dyn Error>> {
+        if self.peek() == Some('-') {
+            self.chars.next();
+            return Ok(Expr::Neg(Box::new(self.unary()?)));
+        }
+        self.power()
+    }
+    // right associative, so 2^3^2 is 2^9
+    fn power(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let base = self.atom()?;
+        if self.peek() == Some('^') {
+            self.chars.next();
+            return Ok(Expr::Binary('^', Box::new(base), Box::new(self.unary()?)));
+        }
+        Ok(base)
+    }
+    fn atom(&mut self) -> Result<Expr, Box<dyn Error>> {
+        match self.peek().ok_or("expression ends early")? {
+            '(' => {
+                self.chars.next();
+                let out = self.sum()?;
+                if self.peek() != Some(')') {
+                    return Err("missing ')'".into());
+                }
+                self.chars.next();
+                Ok(out)
+            }
+            c if c.is_ascii_digit() || c == '.' => {
+                let mut number = String::new();
+                while let Some(c) = self.chars.next_if(|c| c.is_ascii_digit() || *c == '.') {
+                    number.push(c);
+                }
+                Ok(Expr::Number(number.parse()?))
+            }
+            c if c.is_ascii_alphabetic() => {
+                let mut name = String::new();
+                while let Some(c) = self.chars.next_if(|c| c.is_ascii_alphanumeric()) {
+                    name.push(c.to_ascii_lowercase());
+                }
+                match name.as_str() {
+                    "x" => Ok(Expr::X),
+                    "y" => Ok(Expr::Y),
+                    "pi" => Ok(Expr::Number(PI)),
+                    "sin" | "cos" | "tan" | "sqrt" | "abs" | "exp" | "ln" => {
+                        if self.peek() != Some('(') {
+                            return Err(format!("{} needs an argument in brackets", name).into());
+                        }
+                        Ok(Expr::Call(name, Box::new(self.atom()?)))
+                    }
+                    _ => Err(format!("unknown name {}", name).into()),
+                }
+            }
+            c => Err(format!("unexpected {}", c).into()),
+        }
+    }
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Expr, Box<dyn Error>> {
+        let mut parser = ExprParser {
+            chars: text.chars().peekable(),
+        };
+        let out = parser.sum()?;
+        if let Some(c) = parser.peek() {
+            return Err(format!("unexpected {}", c).into());
+        }
+        Ok(out)
+    }
+    pub fn eval(&self, x: f32, y: f32) -> f32 {
+        match self {
+            Expr::Number(n) => *n,
+            Expr::X => x,
+            Expr::Y => y,
+            Expr::Neg(e) => -e.eval(x, y),
+            Expr::Binary(op, a, b) => {
+                let (a, b) = (a.eval(x, y), b.eval(x, y));
+                match op {
+                    '+' => a + b,
+                    '-' => a - b,
+                    '*' => a * b,
+                    '/' => a / b,
+                    _ => a.powf(b),
+                }
+            }
+            Expr::Call(name, e) => {
+                let e = e.eval(x, y);
+                match name.as_str() {
+                    "sin" => e.sin(),
+                    "cos" => e.cos(),
+                    "tan" => e.tan(),
+                    "sqrt" => e.sqrt(),
+                    "abs" => e.abs(),
+                    "exp" => e.exp(),
+                    _ => e.ln(),
+                }
+            }
+        }
+    }
+}
+
+// grey levels from 0 to 1 stretched over a rectangle of the bed, the first row is the back (max y)
+#[derive(Clone, Debug, PartialEq)]
+pub struct HeightMap {
+    pub values: Vec<f32>,
+    pub width: usize,
+    pub height: usize,
+    // (x_min, y_min, x_max, y_max)
+    pub extent: (f32, f32, f32, f32),
+}
+
+impl HeightMap {
+    // bilinear, points off the map take the nearest edge
+    pub fn sample(&self, x: f32, y: f32) -> f32 {
+        let (x0, y0, x1, y1) = self.extent;
+        let u = ((x - x0) / (x1 - x0)).clamp(0.0, 1.0) * (self.width - 1) as f32;
+        let v = ((y1 - y) / (y1 - y0)).clamp(0.0, 1.0) * (self.height - 1) as f32;
+        let (i, j) = (u.floor() as usize, v.floor() as usize);
+        let (i1, j1) = ((i + 1).min(self.width - 1), (j + 1).min(self.height - 1));
+        let (fu, fv) = (u - i as f32, v - j as f32);
+        let at = |i: usize, j: usize| self.values[j * self.width + i];
+        let top = at(i, j) * (1.0 - fu) + at(i1, j) * fu;
+        let bottom = at(i, j1) * (1.0 - fu) + at(i1, j1) * fu;
+        top * (1.0 - fv) + bottom * fv
+    }
+}
+
+// how far up to push the path at each xy
+#[derive(Clone, Debug, PartialEq)]
+pub enum Surface {
+    // waves running along the direction at angle degrees from the x axis
+    Sine {
+        amplitude: f32,
+        wavelength: f32,
+        angle: f32,
+    },
+    // white is amplitude mm up, black stays put
+    HeightMap {
+        map: HeightMap,
+        amplitude: f32,
+    },
+    Expression(Expr),
+}
+
+impl Surface {
+    pub fn offset(&self, x: f32, y: f32) -> f32 {
+        match self {
+            Surface::Sine {
+                amplitude,
+                wavelength,
+                angle,
+            } => {
+                let (sin, cos) = (angle * PI / 180.0).sin_cos();
+                amplitude * (2.0 * PI * (x * cos + y * sin) / wavelength).sin()
+            }
+            Surface::HeightMap { map, amplitude } => amplitude * map.sample(x, y),
+            Surface::Expression(expr) => expr.eval(x, y),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeformReport {
+    pub moved: usize,
+    // moves added by splitting long extrusions
+    pub added: usize,
+    // moves from the deformed ones on that go into material printed before them
+    pub violations: Vec<Collision>,
+}
+
+impl Parsed {
+    // pushes the vertices up or down by the surface, splitting extrusions longer than
+    // max_segment in xy first so they follow it, flows follow the new 3d lengths
+    pub fn deform(
+        &mut self,
+        vertices: &HashSet<Id>,
+        surface: &Surface,
+        max_segment: f32,
+        clearance: f32,
+    ) -> DeformReport {
+        let mut vertices = vertices.clone();
+        let mut added = 0;
+        if max_segment > 0.0 {
+            let long = vertices
+                .iter()
+                .filter(|id| self.vertices.contains_key(id))
+                .filter_map(|id| {
+                    let v = self.vertices.get(id).unwrap();
+                    let from = v.get_from(self);
+                    let xy = ((v.to.x - from.x).powi(2) + (v.to.y - from.y).powi(2)).sqrt();
+                    (v.prev.is_some() && xy > max_segment)
+                        .then(|| (*id, (xy / max_segment).ceil() as u32))
+                })
+                .collect::<Vec<(Id, u32)>>();
+            for (id, count) in long {
+                let new = self.subdivide_vertex(&id, count);
+                added += new.len();
+                vertices.extend(new);
+            }
+            self.set_counts();
+        }
+        let moved = self.modify(&Feature::Vertices(vertices.clone()), |pos| {
+            pos.z += surface.offset(pos.x, pos.y)
+        });
+        let first = self
+            .lines
+            .iter()
+            .position(|id| vertices.contains(id))
+            .unwrap_or(self.lines.len());
+        let params = CollisionParams {
+            clearance,
+            suggest: false,
+            z_hop: 0.0,
+        };
+        let violations = self
+            .collisions(&params)
+            .into_iter()
+            .filter(|c| c.line > first && matches!(c.kind, CollisionKind::BelowPrinted(_)))
+            .filter(|c| self.vertices.get(&c.id).unwrap().label != Label::Home)
+            .collect();
+        DeformReport {
+            moved,
+            added,
+            violations,
+        }
+    }
+}
+
+#[test]
+fn expr_test() {
+    let expr = Expr::parse("0.5 * sin(x / 2) + -y^2^0.5 - (1 - PI)").expect("failed to parse");
+    let want = 0.5 * (3.0f32 / 2.0).sin() - 2.0f32.powf(2.0f32.sqrt()) - (1.0 - PI);
+    assert!((expr.eval(3.0, 2.0) - want).abs() < 1e-5);
+    assert!(Expr::parse("2 * (x + 1").is_err());
+    assert!(Expr::parse("foo(x)").is_err());
+    assert!(Expr::parse("x y").is_err());
+    let map = HeightMap {
+        values: vec![0.0, 1.0, 0.0, 0.0],
+        width: 2,
+        height: 2,
+        extent: (0.0, 0.0, 10.0, 10.0),
+    };
+    assert_eq!(map.sample(10.0, 10.0), 1.0);
+    assert_eq!(map.sample(5.0, 5.0), 0.25);
+    assert_eq!(map.sample(-5.0, 0.0), 0.0);
+}
+
+#[test]
+fn deform_test() {
+    // two layers of a 20mm line, only the first one gets deformed
+    let gcode = "G28\nG1 X0 Y0 Z0.2 F1200\nG1 X20 E2\nG1 Z0.4\nG1 X0 E2";
+    let mut gcode = super::read(gcode, true).expect("failed to parse");
+    let first = gcode.lines[1..3].iter().copied().collect::<HashSet<Id>>();
+    let bump = Surface::Expression(Expr::parse("x / 20").unwrap());
+    let report = gcode.deform(&first, &bump, 5.0, 0.0);
+    assert_eq!(report.moved, 5);
+    assert_eq!(report.added, 3);
+    let layer = gcode
+        .lines
+        .iter()
+        .map(|id| *gcode.vertices.get(id).unwrap())
+        .filter(|v| v.extrusion_move() && v.to.z < 1.3 && v.count < 6)
+        .collect::<Vec<_>>();
+    assert_eq!(layer.len(), 4);
+    for v in &layer {
+        assert_eq!(v.label, Label::NonPlanarExtrusion);
+        let length = gcode.dist_from_prev(&v.id);
+        assert!((v.to.e / length - 0.1).abs() < 1e-4);
+    }
+    let end = layer.last().unwrap().to;
+    assert!((end.z - 1.2).abs() < 1e-5);
+    // the undeformed second layer now runs through the raised first one
+    assert!(!report.violations.is_empty());
+    assert!(report
+        .violations
+        .iter()
+        .all(|c| c.line > 6 && matches!(c.kind, CollisionKind::BelowPrinted(_))));
+    let sine = Surface::Sine {
+        amplitude: 1.0,
+        wavelength: 8.0,
+        angle: 90.0,
+    };
+    assert!((sine.offset(3.0, 2.0) - 1.0).abs() < 1e-5);
+}
@@ -0,0 +1,441 @@
+use super::{file_reader, Id, Instruction, Label, Parsed, Pos, Vertex, Word, G1};
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+};
+
+// a part of the gcode to edit, found as runs of consecutive lines
+#[derive(Clone, Debug, PartialEq)]
+pub enum Feature {
+    // any lines, instructions included
+    Vertices(HashSet<Id>),
+    Shape(Id),
+    // moves ending between the two heights, inclusive
+    Layers(f32, f32),
+    Label(Label),
+}
+
+// gcode text split into words, refusing lines that can't be placed mid-file
+fn parse_text(text: &str) -> Result<Vec<Vec<Word>>, Box<dyn Error>> {
+    let mut out = Vec::new();
+    for line in file_reader::parse_str(text) {
+        let mut words = file_reader::split_line(&line);
+        if words.is_empty() {
+            continue;
+        }
+        words.reverse();
+        let Word(letter, number, _) = words.last().unwrap();
+        match (*letter, number.round() as i32) {
+            ('G', 28) => return Err("homing can't be inserted".into()),
+            // the file is written in one mode, so the text has to be in the file's mode
+            ('G', 90) | ('G', 91) | ('M', 82) | ('M', 83) => {
+                return Err("mode changes can't be inserted".into())
+            }
+            _ => out.push(words),
+        }
+    }
+    Ok(out)
+}
+
+// the extruder position a G92 sets, with no axes given everything is zeroed
+fn g92_e(ins: &Instruction, abs_e: f32) -> Option<f32> {
+    if ins.first_word.0 != 'G' || ins.first_word.1.round() as i32 != 92 {
+        return None;
+    }
+    Some(
+        ins.params
+            .iter()
+            .flatten()
+            .find(|w| w.0 == 'E')
+            .map_or(if ins.params.is_none() { 0.0 } else { abs_e }, |w| w.1),
+    )
+}
+
+impl Parsed {
+    fn in_feature(&self, feature: &Feature, id: &Id, shape: &HashSet<Id>) -> bool {
+        let v = self.vertices.get(id);
+        match feature {
+            Feature::Vertices(ids) => ids.contains(id),
+            Feature::Shape(_) => shape.contains(id),
+            Feature::Layers(min, max) => {
+                v.is_some_and(|v| v.to.z >= min - f32::EPSILON && v.to.z <= max + f32::EPSILON)
+            }
+            Feature::Label(label) => v.is_some_and(|v| v.label == *label),
+        }
+    }
+    // first and last line of every run of the feature, instructions inside a run belong to it
+    pub fn occurrences(&self, feature: &Feature) -> Vec<(usize, usize)> {
+        let shape = match feature {
+            Feature::Shape(id) => self
+                .shapes
+                .iter()
+                .find(|s| s.id == *id)
+                .map(|s| s.lines.iter().copied().collect())
+                .unwrap_or_default(),
+            _ => HashSet::new(),
+        };
+        let mut out = Vec::new();
+        let mut run: Option<(usize, usize)> = None;
+        for (i, id) in self.lines.iter().enumerate() {
+            if self.in_feature(feature, id, &shape) {
+                run = Some((run.map_or(i, |r| r.0), i));
+            } else if self.vertices.contains_key(id) {
+                out.extend(run.take());
+            }
+        }
+        out.extend(run);
+        out
+    }
+    // the lines of the feature in file order
+    pub fn select(&self, feature: &Feature) -> Vec<Id> {
+        self.occurrences(feature)
+            .iter()
+            .flat_map(|(a, b)| self.lines[*a..=*b].iter().copied())
+            .collect()
+    }
+    // the move's length, flow and id, kept to rescale its flow once its start or end moves
+    fn extent(&self, id: &Id) -> Option<(Id, f32, f32)> {
+        let v = self.vertices.get(id)?;
+        v.prev?;
+        Some((*id, self.dist_from_prev(id), v.to.e))
+    }
+    // the first move at or after line i
+    fn following(&self, i: usize) -> Option<(Id, f32, f32)> {
+        let id = self.lines[i.min(self.lines.len())..]
+            .iter()
+            .find(|id| self.vertices.contains_key(id))?;
+        self.extent(id)
+    }
+    // the move each position in the file continues from, checked for all of them before an
+    // edit changes anything so it can't stop halfway
+    fn starts(&self, at: &[usize]) -> Result<Vec<Id>, Box<dyn Error>> {
+        if at.is_empty() {
+            return Err("feature not found".into());
+        }
+        at.iter()
+            .map(|i| {
+                self.lines[..*i]
+                    .iter()
+                    .rev()
+                    .find(|id| self.vertices.contains_key(id))
+                    .copied()
+                    .ok_or_else(|| "can't edit before homing".into())
+            })
+            .collect()
+    }
+    // where the extruder is in absolute e just before lines[i], counted like emit counts it
+    fn abs_e_before(&self, i: usize) -> f32 {
+        let mut abs_e = 0.0;
+        for line in &self.lines[..i] {
+            if let Some(v) = self.vertices.get(line) {
+                abs_e += v.to.e;
+            } else if let Some(e) = self
+                .instructions
+                .get(line)
+                .and_then(|ins| g92_e(ins, abs_e))
+            {
+                abs_e = e;
+            }
+        }
+        abs_e
+    }
+    // places parsed lines at lines[i], moves continue from prev, the move the nozzle is at
+    // e in absolute files is read from where the extruder is at i, like reading the file does
+    // links are left for finish_edit
+    fn place(&mut self, i: usize, mut prev: Id, words: &[Vec<Word>]) -> Vec<Id> {
+        let mut abs_e = if self.rel_e {
+            0.0
+        } else {
+            self.abs_e_before(i)
+        };
+        let mut new = Vec::new();
+        for words in words {
+            let mut words = words.clone();
+            let Word(letter, number, _) = words.last().unwrap();
+            if (*letter, number.round() as i32) == ('G', 1) {
+                words.pop();
+                let mut g1 = G1::build(words);
+                if !self.rel_e {
+                    if let Some(e) = g1.e {
+                        g1.e = Some(e - abs_e);
+                        abs_e = e;
+                    }
+                }
+                let vrtx = Vertex::build(self, &prev, g1);
+                prev = vrtx.id;
+                new.push(vrtx.id);
+                self.insert_vertex(vrtx);
+            } else {
+                let ins = Instruction::build(words);
+                if let Some(e) = g92_e(&ins, abs_e) {
+                    abs_e = e;
+                }
+                new.push(self.add_instruction(ins));
+            }
+        }
+        self.lines_mut().splice(i..i, new.iter().copied());
+        new
+    }
+    // relinks after an edit, then rescales the flow of moves whose length changed
+    // unless the edit set their flow itself
+    fn finish_edit(&mut self, moved: Vec<(Id, f32, f32)>) {
+        self.set_counts();
+        for (id, len, e) in moved {
+            let Some(v) = self.vertices.get(&id) else {
+                continue;
+            };
+            if v.prev.is_none() {
+                continue;
+            }
+            let new_len = self.dist_from_prev(&id);
+            let v = self.vertex_mut(&id).unwrap();
+            if v.extrusion_move() && v.to.e == e && len > f32::EPSILON {
+                v.to.e *= new_len / len;
+            }
+            self.relabel(&id);
+        }
+        self.assign_shapes();
+    }
+    // inserts the text ahead of every occurrence of the feature, returns the new line ids
+    pub fn insert_before(
+        &mut self,
+        feature: &Feature,
+        text: &str,
+    ) -> Result<Vec<Id>, Box<dyn Error>> {
+        let words = parse_text(text)?;
+        let runs = self.occurrences(feature);
+        let at = runs.iter().map(|r| r.0).collect::<Vec<usize>>();
+        let starts = self.starts(&at)?;
+        let mut new = Vec::new();
+        let mut moved = Vec::new();
+        for (i, prev) in at.into_iter().zip(starts).rev() {
+            moved.extend(self.following(i));
+            new.extend(self.place(i, prev, &words));
+        }
+        self.finish_edit(moved);
+        Ok(new)
+    }
+    pub fn insert_after(
+        &mut self,
+        feature: &Feature,
+        text: &str,
+    ) -> Result<Vec<Id>, Box<dyn Error>> {
+        let words = parse_text(text)?;
+        let runs = self.occurrences(feature);
+        let at = runs.iter().map(|r| r.1 + 1).collect::<Vec<usize>>();
+        let starts = self.starts(&at)?;
+        let mut new = Vec::new();
+        let mut moved = Vec::new();
+        for (i, prev) in at.into_iter().zip(starts).rev() {
+            moved.extend(self.following(i));
+            new.extend(self.place(i, prev, &words));
+        }
+        self.finish_edit(moved);
+        Ok(new)
+    }
+    // edits the end point of every move in the feature, returns how many were changed
+    // flows are rescaled to the new lengths, including the moves right after the feature
+    pub fn modify(&mut self, feature: &Feature, mut f: impl FnMut(&mut Pos)) -> usize {
+        let members = self
+            .select(feature)
+            .into_iter()
+            .filter(|id| self.vertices.contains_key(id) && self.vertices[id].label != Label::Home)
+            .collect::<Vec<Id>>();
+        let mut moved = HashMap::new();
+        for id in &members {
+            let v = self.vertices.get(id).unwrap();
+            for id in std::iter::once(*id).chain(v.next) {
+                if let Some((id, len, e)) = self.extent(&id) {
+                    moved.insert(id, (len, e));
+                }
+            }
+        }
+        for id in &members {
+            f(&mut self.vertex_mut(id).unwrap().to);
+        }
+        self.finish_edit(
+            moved
+                .into_iter()
+                .map(|(id, (len, e))| (id, len, e))
+                .collect(),
+        );
+        members.len()
+    }
+    // swaps every occurrence of the feature for the text, returns the new line ids
+    pub fn replace_with(
+        &mut self,
+        feature: &Feature,
+        text: &str,
+    ) -> Result<Vec<Id>, Box<dyn Error>> {
+        let words = parse_text(text)?;
+        let runs = self.occurrences(feature);
+        let at = runs.iter().map(|r| r.0).collect::<Vec<usize>>();
+        // runs are split by moves outside the feature, so removing one leaves the starts of the
+        // ones before it
+        let starts = self.starts(&at)?;
+        let mut new = Vec::new();
+        let mut moved = Vec::new();
+        for ((first, last), prev) in runs.into_iter().zip(starts).rev() {
+            moved.extend(self.following(last + 1));
+            for id in self.lines_mut().drain(first..=last).collect::<Vec<Id>>() {
+                self.remove_vertex(&id);
+                self.remove_instruction(&id);
+            }
+            new.extend(self.place(first, prev, &words));
+        }
+        self.finish_edit(moved);
+        Ok(new)
+    }
+}
+
+#[cfg(test)]
+const SQUARE: &str = "G28\nG1 X10 Y10 Z0.2 F1200\nG1 X20 E1\nM106 S255\nG1 Y20 E1\nG1 E-1\nG1 Z0.4\nG1 X10 E1\nG1 Y10 E1";
+
+#[cfg(test)]
+fn check_links(gcode: &Parsed) {
+    let vertices = gcode
+        .lines
+        .iter()
+        .filter(|id| gcode.vertices.contains_key(id))
+        .collect::<Vec<&Id>>();
+    for (i, id) in vertices.iter().enumerate() {
+        let v = gcode.vertices.get(id).unwrap();
+        assert_eq!(v.count as usize, i);
+        assert_eq!(v.prev, i.checked_sub(1).map(|i| *vertices[i]));
+        assert_eq!(v.next, vertices.get(i + 1).map(|id| **id));
+    }
+    assert_eq!(
+        gcode.shapes.iter().map(|s| s.lines.len()).sum::<usize>()
+            + gcode.vertices.values().filter(|v| v.change_move()).count(),
+        gcode.lines.len()
+    );
+}
+
+#[test]
+fn occurrences_test() {
+    let gcode = super::read(SQUARE, true).expect("failed to parse");
+    let l = &gcode.lines;
+    // the fan instruction sits inside the run of extrusions
+    assert_eq!(
+        gcode.occurrences(&Feature::Label(Label::PlanarExtrustion)),
+        vec![(2, 4), (7, 8)]
+    );
+    assert_eq!(gcode.occurrences(&Feature::Layers(0.3, 1.0)), vec![(6, 8)]);
+    assert_eq!(
+        gcode.occurrences(&Feature::Vertices(HashSet::from([l[2], l[7]]))),
+        vec![(2, 2), (7, 7)]
+    );
+    let shape = gcode.shapes[0].id;
+    assert_eq!(gcode.select(&Feature::Shape(shape)), gcode.shapes[0].lines);
+}
+
+#[test]
+fn insert_test() {
+    let mut gcode = super::read(SQUARE, true).expect("failed to parse");
+    let original = gcode.lines.clone();
+    let new = gcode
+        .insert_before(
+            &Feature::Vertices(HashSet::from([original[4]])),
+            "M106 S0 ; fan off\n\nG1 X25 Y15\nG1 X20 Y10 E0.5",
+        )
+        .expect("failed to insert");
+    assert_eq!(new.len(), 3);
+    assert!(gcode.instructions.contains_key(&new[0]));
+    assert_eq!(&gcode.lines[4..7], &new[..]);
+    check_links(&gcode);
+    let v = gcode.vertices.get(&new[1]).unwrap();
+    assert_eq!((v.to.x, v.to.z, v.to.f), (25.0, 0.2, 1200.0));
+    // the move after the insertion starts where it did before, so keeps its flow
+    assert_eq!(gcode.vertices.get(&original[4]).unwrap().to.e, 1.0);
+    let new = gcode
+        .insert_after(&Feature::Label(Label::PlanarExtrustion), "G1 E-0.8")
+        .expect("failed to insert");
+    assert_eq!(new.len(), 3);
+    assert_eq!(gcode.lines.last(), Some(&new[0]));
+    check_links(&gcode);
+    assert!(gcode
+        .insert_after(&Feature::Layers(0.4, 0.4), "G28")
+        .is_err());
+    assert!(gcode
+        .insert_before(&Feature::Layers(5.0, 6.0), "G1 X1")
+        .is_err());
+    assert!(gcode
+        .insert_after(&Feature::Layers(0.4, 0.4), "M83\nG1 E1")
+        .is_err());
+}
+
+#[test]
+fn insert_absolute_test() {
+    use super::emit::Emit;
+    let gcode = "G28\nM82\nG1 X10 Y10 Z0.2 F1200\nG1 X20 E1\nG1 Y20 E2\nG92 E0\nG1 X10 E1";
+    let mut gcode = super::read(gcode, true).expect("failed to parse");
+    let l = gcode.lines.clone();
+    // the text is in the file's absolute e, from where the extruder is at each point
+    let new = gcode
+        .insert_before(&Feature::Vertices(HashSet::from([l[3]])), "G1 Y15 E1.5")
+        .expect("failed to insert");
+    assert_eq!(gcode.vertices.get(&new[0]).unwrap().to.e, 0.5);
+    let new = gcode
+        .insert_after(
+            &Feature::Vertices(HashSet::from([l[4]])),
+            "G1 E0.5\nG92 E5\nG1 X15 E5.5",
+        )
+        .expect("failed to insert");
+    let e = |id: &Id| gcode.vertices.get(id).unwrap().to.e;
+    assert_eq!((e(&new[0]), e(&new[2])), (0.5, 0.5));
+    // the move after now starts halfway along, so pushes half as much
+    assert!(gcode
+        .emit(&gcode, false)
+        .ends_with("G1 E0.5 \nG92 E5\nG1 X15 E5.5 \nG1 X10 E6 \n"));
+}
+
+#[test]
+fn modify_test() {
+    let mut gcode = super::read(SQUARE, true).expect("failed to parse");
+    let l = gcode.lines.clone();
+    let changed = gcode.modify(&Feature::Vertices(HashSet::from([l[2]])), |p| p.x = 30.0);
+    assert_eq!(changed, 1);
+    check_links(&gcode);
+    // (10,10)->(30,10) is twice as long, (30,10)->(20,20) is sqrt(2) times as long
+    let e = |gcode: &Parsed, i: usize| gcode.vertices.get(&l[i]).unwrap().to.e;
+    assert!((e(&gcode, 2) - 2.0).abs() < 1e-5);
+    assert!((e(&gcode, 4) - 2.0_f32.sqrt()).abs() < 1e-5);
+    assert_eq!(e(&gcode, 7), 1.0);
+    // moves only in z keep their flow and become non planar
+    gcode.modify(&Feature::Layers(0.4, 0.4), |p| p.z += 0.1);
+    assert_eq!(gcode.vertices.get(&l[6]).unwrap().label, Label::LiftZ);
+    assert_eq!(e(&gcode, 8), 1.0);
+    check_links(&gcode);
+}
+
+#[test]
+fn replace_test() {
+    let mut gcode = super::read(SQUARE, true).expect("failed to parse");
+    let l = gcode.lines.clone();
+    let new = gcode
+        .replace_with(&Feature::Label(Label::Retraction), "G10")
+        .expect("failed to replace");
+    assert_eq!(new.len(), 1);
+    assert!(!gcode.vertices.contains_key(&l[5]));
+    assert_eq!(gcode.lines[5], new[0]);
+    check_links(&gcode);
+    // a shorter replacement for the first extrusion lengthens the move after it
+    let new = gcode
+        .replace_with(&Feature::Vertices(HashSet::from([l[2]])), "G1 X15 E0.5")
+        .expect("failed to replace");
+    assert_eq!(new.len(), 1);
+    check_links(&gcode);
+    let next = gcode.vertices.get(&l[4]).unwrap();
+    assert_eq!(next.prev, Some(new[0]));
+    assert!((next.to.e - 125.0_f32.sqrt() / 10.0).abs() < 1e-5);
+    let total = gcode.vertices.values().map(|v| v.to.e).sum::<f32>();
+    assert!((total - (2.5 + 125.0_f32.sqrt() / 10.0)).abs() < 1e-5);
+    // an edit that can't be done anywhere leaves the file as it was
+    let lines = gcode.lines.clone();
+    let vertices = gcode.vertices.clone();
+    assert!(gcode
+        .replace_with(&Feature::Vertices(HashSet::from([l[0], l[7]])), "G10")
+        .is_err());
+    assert_eq!(gcode.lines, lines);
+    assert_eq!(gcode.vertices, vertices);
+}
@@ -46,6 +46,12 @@ impl Emit for Pos {
 }
 impl Emit for Vertex {
     fn emit(&self, parsed: &Parsed, debug: bool) -> String {
+        self.emit_with_e(parsed, debug, self.to.e)
+    }
+}
+impl Vertex {
+    // writes the move with e as its E word, the relative amount or where the extruder ends up
+    fn emit_with_e(&self, parsed: &Parsed, debug: bool, e: impl std::fmt::Display) -> String {
         if self.to == Pos::home() && self.prev.is_none() {
             return "G28\n".to_string();
         }
@@ -65,7 +71,7 @@ impl Emit for Vertex {
         }
         if self.to.e != 0.0 {
             assert!(self.to.e.is_finite() && !self.to.e.is_nan());
-            out += &format!("E{} ", self.to.e);
+            out += &format!("E{} ", e);
         }
         if from.f != self.to.f {
             assert!(self.to.f.is_finite() && !self.to.f.is_nan());
@@ -93,11 +99,31 @@ impl Emit for Parsed {
             out += "M82\n";
         }
 
+        // absolute e is summed in f64 and rounded so long prints don't drift
+        let mut abs_e: f64 = 0.0;
         for line in &self.lines {
             if let Some(v) = self.vertices.get(line) {
-                out += &v.emit(self, debug);
+                if self.rel_e {
+                    out += &v.emit(self, debug);
+                } else {
+                    abs_e = ((abs_e + v.to.e as f64) * 1e5).round() / 1e5;
+                    out += &v.emit_with_e(self, debug, abs_e);
+                }
             } else {
-                out += &self.instructions.get(line).unwrap().emit(self, debug);
+                let ins = self.instructions.get(line).unwrap();
+                if let Word('G', num, None) = ins.first_word {
+                    if num.round() as i32 == 92 {
+                        abs_e = ins
+                            .params
+                            .iter()
+                            .flatten()
+                            .find(|w| w.0 == 'E')
+                            .map_or(if ins.params.is_none() { 0.0 } else { abs_e }, |w| {
+                                w.1 as f64
+                            });
+                    }
+                }
+                out += &ins.emit(self, debug);
             }
         }
         out
@@ -113,3 +139,31 @@ fn debug() {
     let mut f = File::create("test_debug_output.gcode").expect("failed to create file");
     let _ = f.write_all(gcode.as_bytes());
 }
+
+#[test]
+fn absolute_e_test() {
+    let gcode =
+        "G28\nM82\nG1 X10 Y10 Z0.2 F3000\nG1 X20 E1.5\nG1 E0.5\nG92 E0\nG1 E1\nG1 X10 E2.25";
+    let mut gcode = crate::print_analyzer::read(gcode, true).expect("failed to parse");
+    assert!(!gcode.rel_e);
+    // stored relative, with the reset taken into account
+    let e = gcode
+        .lines
+        .iter()
+        .filter_map(|id| gcode.vertices.get(id))
+        .map(|v| v.to.e)
+        .collect::<Vec<f32>>();
+    assert_eq!(e, [0.0, 0.0, 1.5, -1.0, 1.0, 1.25]);
+    assert!(gcode
+        .emit(&gcode, false)
+        .ends_with("G1 X20 E1.5 \nG1 E0.5 \nG92 E0\nG1 E1 \nG1 X10 E2.25 \n"));
+    let all = gcode.lines.iter().copied().collect();
+    gcode.scale_flow(&all, 2.0);
+    let out = gcode.emit(&gcode, false);
+    assert!(out.starts_with("G90\nM82\n"));
+    assert!(out.ends_with("G1 X20 E3 \nG1 E2 \nG92 E0\nG1 E1 \nG1 X10 E3.5 \n"));
+    // the same moves written relative
+    gcode.rel_e = true;
+    let out = gcode.emit(&gcode, false);
+    assert!(out.ends_with("G1 X20 E3 \nG1 E-1 \nG92 E0\nG1 E1 \nG1 X10 E2.5 \n"));
+}
@@ -0,0 +1,85 @@
+use super::{Id, Label, Parsed};
+use std::collections::HashSet;
+
+// what to do to the feedrate of each selected move, in mm/min
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FeedrateOp {
+    Multiply(f32),
+    Set(f32),
+    Clamp(f32, f32),
+}
+
+impl FeedrateOp {
+    pub fn apply(&self, f: f32) -> f32 {
+        match self {
+            FeedrateOp::Multiply(factor) => (f * factor).round(),
+            FeedrateOp::Set(f) => *f,
+            FeedrateOp::Clamp(min, max) => f.clamp(*min, *max),
+        }
+    }
+}
+
+impl Parsed {
+    // changes the feedrate of the selected moves, returns how many changed
+    // every vertex keeps its own feedrate and emit writes F whenever it differs from the move
+    // before, so the moves after the selection keep theirs
+    // retractions and moves before any feedrate was set are left alone
+    pub fn set_feedrate(&mut self, vertices: &HashSet<Id>, op: FeedrateOp) -> usize {
+        let mut changed = Vec::new();
+        let mut count = 0;
+        for id in vertices {
+            let Some(v) = self.vertex_mut(id) else {
+                continue;
+            };
+            if matches!(
+                v.label,
+                Label::Home | Label::Retraction | Label::DeRetraction | Label::FeedrateChangeOnly
+            ) || !v.to.f.is_finite()
+            {
+                continue;
+            }
+            let f = op.apply(v.to.f).max(1.0);
+            if f != v.to.f {
+                v.to.f = f;
+                changed.extend(v.next);
+                changed.push(*id);
+                count += 1;
+            }
+        }
+        // a feedrate only line can end up setting the feedrate it already has
+        for id in &changed {
+            self.relabel(id);
+        }
+        count
+    }
+}
+
+#[test]
+fn feedrate_test() {
+    use super::emit::Emit;
+    let gcode = "G28\nG1 X10 Y10 Z0.2 F1200\nG1 X20 E1\nG1 E-1 F2400\nG1 X30\nG1 E1\nG1 X40 E1 F1200\nG1 Y20 E1";
+    let mut gcode = super::read(gcode, true).expect("failed to parse");
+    let ids = gcode.lines.clone();
+    let out = gcode.emit(&gcode, false);
+    assert!(out.contains("G1 X20 E1 \nG1 E-1 F2400 \n"));
+    // slowing the first extrusion writes the old feedrate back on the retraction after it
+    let changed = gcode.set_feedrate(&HashSet::from([ids[2]]), FeedrateOp::Multiply(0.5));
+    assert_eq!(changed, 1);
+    let out = gcode.emit(&gcode, false);
+    assert!(out.contains("G1 X20 E1 F600 \nG1 E-1 F2400 \n"));
+    // speeding up the last two only the first of them needs an F
+    let changed = gcode.set_feedrate(&HashSet::from([ids[6], ids[7]]), FeedrateOp::Set(1800.0));
+    assert_eq!(changed, 2);
+    let out = gcode.emit(&gcode, false);
+    assert!(out.ends_with("G1 X40 E1 F1800 \nG1 Y20 E1 \n"));
+    // the retraction and deretraction keep their speed
+    let all = ids.iter().copied().collect::<HashSet<Id>>();
+    gcode.set_feedrate(&all, FeedrateOp::Clamp(1000.0, 2000.0));
+    let f = |i: usize| gcode.vertices.get(&ids[i]).unwrap().to.f;
+    assert_eq!(
+        (1..8).map(f).collect::<Vec<f32>>(),
+        [1200.0, 1000.0, 2400.0, 2000.0, 2400.0, 1800.0, 1800.0]
+    );
+    let out = gcode.emit(&gcode, false);
+    assert!(out.contains("G1 X30 F2000 \nG1 E1 F2400 \nG1 X40 E1 F1800 \n"));
+}
@@ -33,7 +33,7 @@ pub fn split_line(line: &str) -> Vec<Word> {
             }
         }
     }
-    if let Word('N', ..) = out[0] {
+    if let Some(Word('N', ..)) = out.first() {
         out.reverse();
         out.pop();
         out.reverse();
@@ -0,0 +1,136 @@
+use super::{Id, Parsed};
+use std::collections::{HashMap, HashSet};
+use std::f32::consts::PI;
+
+// cross section of the bead laid down by an extrusion move
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bead {
+    pub layer_height: f32,
+    // mm^2
+    pub area: f32,
+    // mm
+    pub width: f32,
+    // mm^3/s, zero if no feedrate has been set yet
+    pub flow: f32,
+}
+
+// height of the layer at z above the layer below it, or above the bed for the first layer
+fn layer_height(layers: &[f32], z: f32) -> f32 {
+    let i = layers.partition_point(|l| *l < z - f32::EPSILON);
+    if i == 0 {
+        z
+    } else {
+        z - layers[i - 1]
+    }
+}
+
+impl Parsed {
+    pub fn filament_area(&self) -> f32 {
+        PI * (self.filament_diameter / 2.0).powi(2)
+    }
+    fn bead_at_height(&self, id: &Id, layer_height: f32) -> Option<Bead> {
+        let v = self.vertices.get(id)?;
+        if !v.extrusion_move() || layer_height < f32::EPSILON {
+            return None;
+        }
+        let length = self.dist_from_prev(id);
+        if length < f32::EPSILON {
+            return None;
+        }
+        let area = v.to.e * self.filament_area() / length;
+        // the bead is modeled as a rectangle with semicircular sides, the same
+        // shape slicers use to turn a requested width into an extrusion amount
+        let width = area / layer_height + layer_height * (1.0 - PI / 4.0);
+        let flow = if v.to.f.is_finite() {
+            area * v.to.f / 60.0
+        } else {
+            0.0
+        };
+        Some(Bead {
+            layer_height,
+            area,
+            width,
+            flow,
+        })
+    }
+    pub fn bead(&self, id: &Id) -> Option<Bead> {
+        let z = self.vertices.get(id)?.to.z;
+        self.bead_at_height(id, layer_height(&self.layers(), z))
+    }
+    pub fn beads(&self) -> HashMap<Id, Bead> {
+        let layers = self.layers();
+        let mut out = HashMap::new();
+        for v in self.vertices.values() {
+            if let Some(bead) = self.bead_at_height(&v.id, layer_height(&layers, v.to.z)) {
+                out.insert(v.id, bead);
+            }
+        }
+        out
+    }
+    // extrusion moves over the given volumetric flow, in file order
+    pub fn flow_warnings(&self, max_flow: f32) -> Vec<Id> {
+        let beads = self.beads();
+        self.lines
+            .iter()
+            .filter(|id| beads.get(id).is_some_and(|b| b.flow > max_flow))
+            .copied()
+            .collect()
+    }
+    // multiplies the filament pushed by the selected extrusion moves, returns how many changed
+    // retractions, deretractions and wipes keep their amounts
+    pub fn scale_flow(&mut self, vertices: &HashSet<Id>, factor: f32) -> usize {
+        let mut changed = Vec::new();
+        for id in vertices {
+            let Some(v) = self.vertex_mut(id) else {
+                continue;
+            };
+            if v.extrusion_move() {
+                v.to.e *= factor;
+                changed.push(*id);
+            }
+        }
+        // a factor of 0 turns the moves into travels
+        for id in &changed {
+            self.relabel(id);
+        }
+        changed.len()
+    }
+}
+
+#[test]
+fn bead_test() {
+    let gcode = "G28\nG1 X10 Y10 Z0.2 F3000\nG1 X20 E0.5 F1200\nG1 Z0.4\nG1 X10 E0.5 F6000";
+    let gcode = super::read(gcode, true).expect("failed to parse");
+    let ids = gcode.lines.clone();
+    let first = gcode.bead(&ids[2]).expect("no bead for extrusion");
+    let second = gcode.bead(&ids[4]).expect("no bead for extrusion");
+    assert!(gcode.bead(&ids[1]).is_none());
+    assert!((first.layer_height - 0.2).abs() < 1e-5);
+    assert!((second.layer_height - 0.2).abs() < 1e-5);
+    let area = 0.5 * gcode.filament_area() / 10.0;
+    assert!((first.area - area).abs() < 1e-5);
+    assert!((first.width - (area / 0.2 + 0.2 * (1.0 - PI / 4.0))).abs() < 1e-5);
+    assert!((first.flow - area * 20.0).abs() < 1e-4);
+    assert!((second.flow - area * 100.0).abs() < 1e-4);
+    assert_eq!(gcode.flow_warnings(area * 50.0), vec![ids[4]]);
+}
+
+#[test]
+fn scale_flow_test() {
+    let gcode =
+        "G28\nG1 X10 Y10 Z0.2 F3000\nG1 X20 E0.5 F1200\nG1 E-1\nG1 Z0.4\nG1 E1\nG1 X10 E0.5";
+    let mut gcode = super::read(gcode, true).expect("failed to parse");
+    let all = gcode.lines.iter().copied().collect::<HashSet<Id>>();
+    assert_eq!(gcode.scale_flow(&all, 1.1), 2);
+    let e = gcode
+        .lines
+        .iter()
+        .map(|id| gcode.vertices.get(id).unwrap().to.e)
+        .collect::<Vec<f32>>();
+    assert_eq!(e, [0.0, 0.0, 0.55, -1.0, 0.0, 1.0, 0.55]);
+    assert_eq!(gcode.scale_flow(&all, 0.0), 2);
+    assert_eq!(
+        gcode.vertices.get(&gcode.lines[2]).unwrap().label,
+        super::Label::TravelMove
+    );
+}
@@ -0,0 +1,149 @@
+use super::{Id, Parsed};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limits {
+    // mm^3/s
+    pub max_flow: f32,
+    // mm/min, the same units as F
+    pub max_feedrate: f32,
+    // mm/s^2
+    pub max_accel: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LimitKind {
+    Flow,
+    Feedrate,
+    Acceleration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Violation {
+    pub id: Id,
+    // 1-based position in the parsed lines
+    pub line: usize,
+    // 0-based layer index
+    pub layer: usize,
+    pub kind: LimitKind,
+    // amount over the limit, in the units of the limit
+    pub over: f32,
+}
+
+// the acceleration needed to go from speed vi to vf (mm/s) over dist (mm)
+// this ignores junction deviation and assumes the whole move is spent accelerating
+fn accel(vi: f32, vf: f32, dist: f32) -> f32 {
+    (vf.powi(2) - vi.powi(2)).abs() / (2.0 * dist)
+}
+
+impl Parsed {
+    // moves that carry a feedrate and actually go somewhere, in file order
+    fn motion(&self) -> Vec<(usize, Id)> {
+        let mut out = Vec::new();
+        for (i, line) in self.lines.iter().enumerate() {
+            if let Some(v) = self.vertices.get(line) {
+                if v.prev.is_some() && v.to.f.is_finite() && self.dist_from_prev(line) > 0.0 {
+                    out.push((i, *line));
+                }
+            }
+        }
+        out
+    }
+    pub fn check_limits(&self, limits: &Limits) -> Vec<Violation> {
+        let beads = self.beads();
+        let layers = self.layers();
+        let mut out = Vec::new();
+        let mut prev_f: Option<f32> = None;
+        for (i, id) in self.motion() {
+            let v = self.vertices.get(&id).unwrap();
+            let layer = layers
+                .partition_point(|l| *l < v.to.z - f32::EPSILON)
+                .min(layers.len().saturating_sub(1));
+            let mut report = |kind, over| {
+                out.push(Violation {
+                    id,
+                    line: i + 1,
+                    layer,
+                    kind,
+                    over,
+                })
+            };
+            if let Some(bead) = beads.get(&id) {
+                if bead.flow > limits.max_flow {
+                    report(LimitKind::Flow, bead.flow - limits.max_flow);
+                }
+            }
+            if v.to.f > limits.max_feedrate {
+                report(LimitKind::Feedrate, v.to.f - limits.max_feedrate);
+            }
+            if let Some(prev_f) = prev_f {
+                let a = accel(prev_f / 60.0, v.to.f / 60.0, self.dist_from_prev(&id));
+                if a > limits.max_accel {
+                    report(LimitKind::Acceleration, a - limits.max_accel);
+                }
+            }
+            prev_f = Some(v.to.f);
+        }
+        out
+    }
+    // lower F on every move that breaks a limit, returns the number of moves changed
+    pub fn clamp_to_limits(&mut self, limits: &Limits) -> usize {
+        let beads = self.beads();
+        let motion = self.motion();
+        let mut speeds = Vec::with_capacity(motion.len());
+        for (_, id) in &motion {
+            let v = self.vertices.get(id).unwrap();
+            let mut f = v.to.f.min(limits.max_feedrate);
+            if let Some(bead) = beads.get(id) {
+                if bead.area > 0.0 {
+                    f = f.min(limits.max_flow / bead.area * 60.0);
+                }
+            }
+            speeds.push((f / 60.0, self.dist_from_prev(id)));
+        }
+        // forward pass limits speeding up, backward pass limits slowing down
+        for i in 1..speeds.len() {
+            let (prev, _) = speeds[i - 1];
+            let (v, dist) = speeds[i];
+            speeds[i].0 = v.min((prev.powi(2) + 2.0 * limits.max_accel * dist).sqrt());
+        }
+        for i in (1..speeds.len()).rev() {
+            let (v, dist) = speeds[i];
+            let prev = speeds[i - 1].0;
+            speeds[i - 1].0 = prev.min((v.powi(2) + 2.0 * limits.max_accel * dist).sqrt());
+        }
+        let mut count = 0;
+        for ((_, id), (v, _)) in motion.iter().zip(speeds) {
+            let vertex = self.vertex_mut(id).unwrap();
+            // leave a little headroom so float error doesn't flag the clamped move again
+            let f = v * 60.0 * 0.999;
+            if f < vertex.to.f * 0.999 {
+                vertex.to.f = f;
+                count += 1;
+            }
+        }
+        count
+    }
+}
+
+#[test]
+fn limits_test() {
+    let gcode =
+        "G28\nG1 X10 Y10 Z0.2 F3000\nG1 X20 E0.5 F1200\nG1 X30 E0.5 F12000\nG1 X40 E0.5 F1200";
+    let mut gcode = super::read(gcode, true).expect("failed to parse");
+    let limits = Limits {
+        max_flow: 15.0,
+        max_feedrate: 6000.0,
+        max_accel: 1000.0,
+    };
+    let report = gcode.check_limits(&limits);
+    let fast = gcode.lines[3];
+    assert!(report
+        .iter()
+        .any(|r| r.id == fast && r.kind == LimitKind::Feedrate && r.line == 4 && r.layer == 0));
+    assert!(report
+        .iter()
+        .any(|r| r.id == fast && r.kind == LimitKind::Flow));
+    assert!(report.iter().any(|r| r.kind == LimitKind::Acceleration));
+    assert!(gcode.clamp_to_limits(&limits) > 0);
+    assert!(gcode.check_limits(&limits).is_empty());
+}
@@ -1,6 +1,24 @@
+pub mod bounds;
+pub mod collision;
+pub mod deform;
+pub mod edit;
 pub mod emit;
+pub mod feedrate;
 mod file_reader;
-mod transform;
+pub mod flow;
+pub mod limits;
+pub mod overhang;
+pub mod paths;
+pub mod pause;
+pub mod retraction;
+pub mod roles;
+pub mod schedule;
+pub mod seams;
+pub mod svg;
+pub mod transform;
+pub mod wipe;
+pub mod zhop;
+use retraction::RetractParams;
 use std::collections::{HashMap, HashSet};
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
@@ -37,23 +55,15 @@ impl Instruction {
             params: Some(line),
         }
     }
-    pub fn insert_temp_retraction(gcode: &mut Parsed) -> Id {
-        let id = gcode.id_counter.get();
-        let ins = Instruction {
-            first_word: Word('X', f32::NEG_INFINITY, Some(String::from("; retraction"))),
-            params: None,
-        };
-        assert!(gcode.instructions.insert(id, ins).is_none());
-        id
-    }
-    pub fn insert_temp_deretraction(gcode: &mut Parsed) -> Id {
-        let id = gcode.id_counter.get();
-        let ins = Instruction {
-            first_word: Word('X', f32::NEG_INFINITY, Some(String::from("; deretraction"))),
-            params: None,
-        };
-        assert!(gcode.instructions.insert(id, ins).is_none());
-        id
+    pub fn new(first_word: Word, params: Vec<Word>) -> Instruction {
+        Instruction {
+            first_word,
+            params: if params.is_empty() {
+                None
+            } else {
+                Some(params)
+            },
+        }
     }
 }
 
@@ -146,6 +156,9 @@ pub struct Vertex {
     pub id: Id,
     pub count: u32,
     pub label: Label,
+    pub support: Support,
+    // from when the file was read, see classify_roles
+    pub role: Role,
     // this id of previous extrusion move
     pub prev: Option<Id>,
     pub next: Option<Id>,
@@ -163,11 +176,13 @@ impl std::fmt::Debug for Vertex {
 impl Vertex {
     fn build(parsed: &mut Parsed, prev: &Id, g1: G1) -> Vertex {
         let id = parsed.id_counter.get();
-        let p = parsed.vertices.get_mut(prev).unwrap();
+        let p = parsed.vertex_mut(prev).unwrap();
         let mut vrtx = Vertex {
             id,
             count: p.count + 1,
             label: Label::Uninitialized,
+            support: Support::Unknown,
+            role: Role::Unknown,
             to: Pos::build(&p.to, &g1),
             prev: Some(*prev),
             next: p.next,
@@ -190,10 +205,8 @@ impl Vertex {
         let dz = self.to.z - from.z;
         let de = self.to.e;
         self.label = {
-            if self.to.x < 5.0 || self.to.y < 5.0 {
-                Label::PrePrintMove
-            } else if de > 0.0 {
-                if dx.abs() + dy.abs() > 0.0 - f32::EPSILON {
+            if de > 0.0 {
+                if dx.abs() + dy.abs() > f32::EPSILON {
                     if dz.abs() > f32::EPSILON {
                         Label::NonPlanarExtrusion
                     } else {
@@ -250,6 +263,15 @@ impl Shape {
     }
 }
 
+// what lines, vertices and instructions were before the edits since the last take_changes,
+// None for entries that didn't exist, so edits can be undone without a copy of the file
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Changes {
+    pub lines: Option<Vec<Id>>,
+    pub vertices: HashMap<Id, Option<Vertex>>,
+    pub instructions: HashMap<Id, Option<Instruction>>,
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Parsed {
     pub lines: Vec<Id>,
@@ -258,7 +280,9 @@ pub struct Parsed {
     pub shapes: Vec<Shape>,
     pub rel_xyz: bool,
     pub rel_e: bool,
+    pub filament_diameter: f32,
     id_counter: Id,
+    changes: Changes,
 }
 impl Parsed {
     pub fn new() -> Parsed {
@@ -269,7 +293,9 @@ impl Parsed {
             shapes: Vec::new(),
             rel_xyz: false,
             rel_e: true,
+            filament_diameter: 1.75,
             id_counter: Id(0),
+            changes: Changes::default(),
         }
     }
     pub fn build(path: &str, testing: bool) -> Result<Parsed, Box<dyn std::error::Error>> {
@@ -284,6 +310,8 @@ impl Parsed {
         assert!(!lines.is_empty());
         // previous vertex id
         let mut prev: Option<Id> = None;
+        // where the extruder is in absolute e mode, e is stored relative either way
+        let mut abs_e = 0.0;
         for line in lines {
             // parse the line into a vec of Word(char, f32, Option<String>)
             let mut line = file_reader::split_line(&line);
@@ -306,6 +334,8 @@ impl Parsed {
                         id,
                         count: 0,
                         label: Label::Home,
+                        support: Support::Unknown,
+                        role: Role::Unknown,
                         to: Pos::home(),
                         prev: None,
                         next: None,
@@ -317,7 +347,13 @@ impl Parsed {
                 ('G', 1) => {
                     // if prev is None, it means no homing command has been read
                     let p = prev.expect("g1 move from unhomed state");
-                    let g1 = G1::build(line);
+                    let mut g1 = G1::build(line);
+                    if !parsed.rel_e {
+                        if let Some(e) = g1.e {
+                            g1.e = Some(e - abs_e);
+                            abs_e = e;
+                        }
+                    }
                     let vrtx = Vertex::build(&mut parsed, &p, g1);
                     parsed.lines.push(vrtx.id);
                     prev = Some(vrtx.id);
@@ -336,6 +372,13 @@ impl Parsed {
                     parsed.rel_e = true;
                 }
                 _ => {
+                    if (letter, num) == ('G', 92) {
+                        // resetting the extruder position, with no axes given everything is zeroed
+                        abs_e = line
+                            .iter()
+                            .find(|w| w.0 == 'E')
+                            .map_or(if line.is_empty() { 0.0 } else { abs_e }, |w| w.1);
+                    }
                     let word = Word(letter, number, params);
                     line.push(word);
                     let id = parsed.id_counter.get();
@@ -346,8 +389,71 @@ impl Parsed {
             }
         }
         parsed.assign_shapes();
+        parsed.classify_roles();
+        // reading the file isn't an edit
+        parsed.take_changes();
         Ok(parsed)
     }
+    // edits after reading change lines, vertices and instructions through these, which keep
+    // the old value the first time something is changed
+    fn lines_mut(&mut self) -> &mut Vec<Id> {
+        self.changes.lines.get_or_insert_with(|| self.lines.clone());
+        &mut self.lines
+    }
+    fn vertex_mut(&mut self, id: &Id) -> Option<&mut Vertex> {
+        let old = *self.vertices.get(id)?;
+        self.changes.vertices.entry(*id).or_insert(Some(old));
+        self.vertices.get_mut(id)
+    }
+    fn insert_vertex(&mut self, vertex: Vertex) {
+        let old = self.vertices.insert(vertex.id, vertex);
+        self.changes.vertices.entry(vertex.id).or_insert(old);
+    }
+    fn remove_vertex(&mut self, id: &Id) -> Option<Vertex> {
+        let old = self.vertices.remove(id)?;
+        self.changes.vertices.entry(*id).or_insert(Some(old));
+        Some(old)
+    }
+    fn instruction_mut(&mut self, id: &Id) -> Option<&mut Instruction> {
+        let old = self.instructions.get(id)?.clone();
+        self.changes.instructions.entry(*id).or_insert(Some(old));
+        self.instructions.get_mut(id)
+    }
+    fn remove_instruction(&mut self, id: &Id) -> Option<Instruction> {
+        let old = self.instructions.remove(id)?;
+        self.changes
+            .instructions
+            .entry(*id)
+            .or_insert(Some(old.clone()));
+        Some(old)
+    }
+    // the old values of everything edited since the last call
+    pub fn take_changes(&mut self) -> Changes {
+        std::mem::take(&mut self.changes)
+    }
+    // adds an instruction without placing it in lines
+    pub fn add_instruction(&mut self, ins: Instruction) -> Id {
+        let id = self.id_counter.get();
+        assert!(self.instructions.insert(id, ins).is_none());
+        self.changes.instructions.insert(id, None);
+        id
+    }
+    // adds a move without placing it in lines, it gets linked by set_counts and labeled by relabel
+    fn add_vertex(&mut self, to: Pos) -> Id {
+        let id = self.id_counter.get();
+        let vrtx = Vertex {
+            id,
+            count: 0,
+            label: Label::Uninitialized,
+            support: Support::Unknown,
+            role: Role::Unknown,
+            prev: None,
+            next: None,
+            to,
+        };
+        self.insert_vertex(vrtx);
+        id
+    }
     pub fn centroid(&self) -> Vec3 {
         let (mut x, mut y, mut z) = (0.0, 0.0, 0.0);
         let mut count = 0.0;
@@ -364,7 +470,7 @@ impl Parsed {
         z /= count;
         Vec3::new(x, y, z)
     }
-    fn assign_shapes(&mut self) {
+    pub fn assign_shapes(&mut self) {
         let mut out = Vec::new();
         let mut temp_shape = Vec::new();
         let mut layer = -1.0;
@@ -421,57 +527,53 @@ impl Parsed {
         p.to.dist(&v.to)
     }
 
-    pub fn hole_delete(&mut self, lines_to_delete: &mut HashSet<Id>) {
-        let mut temp = Vec::new();
-        for line in &self.lines.clone() {
-            if lines_to_delete.is_empty() {
-                break;
-            }
-            if lines_to_delete.contains(line) {
-                lines_to_delete.remove(line);
-                let v_id = {
-                    let vertex = self.vertices.get_mut(line).unwrap();
-                    vertex.to.e = 0.0;
-                    vertex.label = Label::TravelMove;
-                    vertex.id
-                };
-                let retract = Instruction::insert_temp_retraction(self);
-                let deretract = Instruction::insert_temp_deretraction(self);
-                temp.push(retract);
-                temp.push(v_id);
-                temp.push(deretract);
-            } else {
-                temp.push(*line)
+    // turns the moves into travels and retracts around them
+    pub fn hole_delete(&mut self, lines_to_delete: &mut HashSet<Id>, retract: &RetractParams) {
+        let mut holes = HashSet::new();
+        for line in lines_to_delete.drain() {
+            if let Some(vertex) = self.vertex_mut(&line) {
+                vertex.to.e = 0.0;
+                holes.insert(line);
             }
         }
-        self.lines = temp;
+        for id in &holes {
+            self.relabel(id);
+        }
+        self.retract_travels(&holes, retract);
     }
     pub fn merge_delete(&mut self, lines_to_delete: &mut HashSet<Id>) {
         let mut temp = Vec::new();
+        // moves that now start from somewhere else
+        let mut merged = Vec::new();
 
-        for line in &self.lines {
-            if lines_to_delete.is_empty() {
-                break;
-            }
-            if lines_to_delete.contains(line) {
-                lines_to_delete.remove(line);
+        for line in self.lines.clone() {
+            if lines_to_delete.contains(&line) {
+                lines_to_delete.remove(&line);
                 //  keep track of the prev node of the first vertex deleted in a block of verteces
-                let (_, vertex) = self
-                    .vertices
-                    .remove_entry(line)
+                let vertex = self
+                    .remove_vertex(&line)
                     .expect("removing non-existent vertex");
                 if let Some(n) = vertex.next {
-                    let n = self.vertices.get_mut(&n).unwrap();
+                    merged.push(n);
+                    let n = self.vertex_mut(&n).unwrap();
                     n.prev = vertex.prev;
                 }
                 if let Some(p) = vertex.prev {
-                    let p = self.vertices.get_mut(&p).unwrap();
+                    let p = self.vertex_mut(&p).unwrap();
                     p.next = vertex.next;
                 }
             } else {
                 temp.push(line);
             }
         }
+        *self.lines_mut() = temp;
+        self.set_counts();
+        for id in merged {
+            if self.vertices.contains_key(&id) {
+                self.relabel(&id);
+            }
+        }
+        self.assign_shapes();
     }
 
     pub fn translate(&mut self, id: &Id, dx: f32, dy: f32, dz: f32) {
@@ -486,7 +588,7 @@ impl Parsed {
         let init_flow = self.vertices.get(id).unwrap().to.e;
         let prev_dist = self.dist_from_prev(&prev);
         {
-            let pv = self.vertices.get_mut(&prev).unwrap();
+            let pv = self.vertex_mut(&prev).unwrap();
             pv.to.x += dx;
             pv.to.y += dy;
             pv.to.z += dz;
@@ -494,7 +596,7 @@ impl Parsed {
 
         let new_prev_dist = self.dist_from_prev(&prev);
 
-        let prev = self.vertices.get_mut(&prev).unwrap();
+        let prev = self.vertex_mut(&prev).unwrap();
 
         let mut scale = new_prev_dist / prev_dist;
         if scale.is_infinite() || scale.is_nan() {
@@ -507,7 +609,7 @@ impl Parsed {
         if scale.is_infinite() || scale.is_nan() {
             scale = 0.0;
         }
-        let v = self.vertices.get_mut(id).unwrap();
+        let v = self.vertex_mut(id).unwrap();
         v.to.e = init_flow * scale;
     }
     fn insert_lines_before(&mut self, mut lines: Vec<Id>, id: &Id) {
@@ -519,18 +621,19 @@ impl Parsed {
             i += 1;
         }
         while let Some(line) = lines.pop() {
-            self.lines.insert(i, line);
+            self.lines_mut().insert(i, line);
         }
     }
-    fn subdivide_vertex(&mut self, id: &Id, count: u32) {
+    // splits the move into count equal moves, returns the ids of the ones added before it
+    fn subdivide_vertex(&mut self, id: &Id, count: u32) -> Vec<Id> {
         if count < 1 {
-            return;
+            return Vec::new();
         }
         // this is assuming relative e
         let v = self.vertices.get(id).unwrap();
         // don't subdivide moves with no extrustion
         if v.label != Label::PlanarExtrustion && v.label != Label::NonPlanarExtrusion {
-            return;
+            return Vec::new();
         }
         let (xi, yi, zi) = {
             if v.prev.is_none() {
@@ -541,6 +644,7 @@ impl Parsed {
             }
         };
         let (xf, yf, zf, ef, f) = (v.to.x, v.to.y, v.to.z, v.to.e, v.to.f);
+        let (support, role) = (v.support, v.role);
         let countf = count as f32;
         let (step_x, step_y, step_z) = ((xf - xi) / countf, (yf - yi) / countf, (zf - zi) / countf);
         let mut prev = v.prev;
@@ -552,6 +656,8 @@ impl Parsed {
                 id: self.id_counter.get(),
                 count: 0, // this then needs to be counted and set
                 label: Label::Uninitialized,
+                support,
+                role,
                 prev,
                 to: Pos {
                     x: xi + (step_x * i),
@@ -563,7 +669,7 @@ impl Parsed {
                 next: None, // this gets set as part of set_counts
             };
             new.label(self);
-            self.vertices.insert(new.id, new.clone());
+            self.insert_vertex(new);
             prev = Some(new.id);
             new_ids.push(new.id);
             vec.push(new);
@@ -571,10 +677,11 @@ impl Parsed {
         for id in &new_ids {
             prev = Some(*id);
         }
-        self.insert_lines_before(new_ids, id);
-        let v = self.vertices.get_mut(id).unwrap();
+        self.insert_lines_before(new_ids.clone(), id);
+        let v = self.vertex_mut(id).unwrap();
         v.to.e = ef / countf;
         v.prev = prev;
+        new_ids
     }
     pub fn subdivide_vertices(&mut self, vertices: HashSet<Id>, count: u32) {
         for id in vertices {
@@ -611,6 +718,18 @@ impl Parsed {
         }
         out
     }
+    pub fn layers(&self) -> Vec<f32> {
+        // the z heights of all planar extrusion, sorted and deduplicated
+        let mut out = self
+            .vertices
+            .values()
+            .filter(|v| v.label == Label::PlanarExtrustion)
+            .map(|v| v.to.z)
+            .collect::<Vec<f32>>();
+        out.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        out.dedup_by(|a, b| (*a - *b).abs() < f32::EPSILON);
+        out
+    }
     pub fn write_to_file(&self, path: &str) -> Result<(), std::io::Error> {
         use std::fs::File;
         let out = self.emit(self, false);
@@ -619,25 +738,45 @@ impl Parsed {
         println!("save successful");
         Ok(())
     }
+    // relinks prev and next of every vertex in line order and recounts them
+    // only the vertices whose links change are touched
     fn set_counts(&mut self) {
-        let mut count = 0;
-        let mut next = None;
-        for line in &self.lines {
-            if let Some(v) = self.vertices.get_mut(line) {
-                v.count = count;
-                v.next = next;
-                next = Some(v.id);
-                count += 1;
+        let order = self
+            .lines
+            .iter()
+            .filter(|id| self.vertices.contains_key(id))
+            .copied()
+            .collect::<Vec<Id>>();
+        for (i, id) in order.iter().enumerate() {
+            let links = (
+                i as u32,
+                i.checked_sub(1).map(|p| order[p]),
+                order.get(i + 1).copied(),
+            );
+            let v = self.vertices.get(id).unwrap();
+            if (v.count, v.prev, v.next) != links {
+                let v = self.vertex_mut(id).unwrap();
+                (v.count, v.prev, v.next) = links;
             }
         }
     }
+    fn relabel(&mut self, id: &Id) {
+        let mut v = *self.vertices.get(id).unwrap();
+        if v.label == Label::Home {
+            return;
+        }
+        let old = v.label;
+        v.label(self);
+        if v.label != old {
+            self.vertex_mut(id).unwrap().label = v.label;
+        }
+    }
 }
 
 #[derive(bevy::prelude::Component, Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Label {
     Uninitialized,
     Home,
-    PrePrintMove,
     TravelMove,
     PlanarExtrustion,
     NonPlanarExtrusion,
@@ -650,6 +789,28 @@ pub enum Label {
     FeedrateChangeOnly,
 }
 
+// how well an extrusion move is held up by the layer below it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Support {
+    Unknown,
+    Supported,
+    // percentage of the bead hanging past the material below
+    Overhang(f32),
+    // unsupported along its length but anchored at both ends
+    Bridge,
+}
+
+// what an extrusion move is part of, independent of its motion label
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Unknown,
+    OuterPerimeter,
+    InnerPerimeter,
+    Infill,
+    SkirtBrim,
+    Purge,
+}
+
 #[cfg(test)]
 #[test]
 fn tran_test() {
@@ -679,6 +840,32 @@ fn no_home_test() {
 fn double_home() {
     let _ = read("G28\nG28\nG1 x1\ng1y1\ng1e2.222\ng1z1\n", true).expect("failed to parse");
 }
+#[test]
+fn label_test() {
+    // moves that only push filament are unretracts, not extrusions
+    let gcode = "G28\nG1 X10 Y10 Z0.2 F1200\nG1 X20 E1\nG1 E-1\nG1 E1\nG1 X30 Z0.4 E1\nG1 X40\nG1 Y20 E-0.5\nG1 Z0.6\nG1 F600";
+    let gcode = read(gcode, true).expect("failed to parse");
+    let labels = gcode
+        .lines
+        .iter()
+        .map(|id| gcode.vertices.get(id).unwrap().label)
+        .collect::<Vec<Label>>();
+    assert_eq!(
+        labels,
+        [
+            Label::Home,
+            Label::LiftZ,
+            Label::PlanarExtrustion,
+            Label::Retraction,
+            Label::DeRetraction,
+            Label::NonPlanarExtrusion,
+            Label::TravelMove,
+            Label::Wipe,
+            Label::LiftZ,
+            Label::FeedrateChangeOnly
+        ]
+    );
+}
 
 pub fn read(path: &str, raw_str: bool) -> Result<Parsed, Box<dyn std::error::Error>> {
     Parsed::build(path, raw_str)
@@ -696,11 +883,6 @@ fn _vertex_filter(gcode: &Parsed, f: fn(&Vertex) -> bool) -> HashSet<Id> {
     out
 }
 
-// fn insert_before(feature)
-// fn modify(feature)
-// fn replace_with(feature, gcode_sequence)
-// fn insert_after(feature)
-
 #[cfg(test)]
 use std::fs::File;
 use std::io::Write;
@@ -0,0 +1,178 @@
+use super::{collision::PrintedMap, Id, Instruction, Parsed, Support, Vertex, Word};
+
+// overhangs hanging out less than this percentage count as supported
+pub const OVERHANG_THRESHOLD: f32 = 10.0;
+
+impl Vertex {
+    pub fn unsupported(&self) -> bool {
+        self.extrusion_move()
+            && match self.support {
+                Support::Overhang(p) => p >= OVERHANG_THRESHOLD,
+                Support::Bridge => true,
+                _ => false,
+            }
+    }
+}
+
+fn classify(below: &PrintedMap, a: (f32, f32), b: (f32, f32), half_width: f32) -> Support {
+    let length = ((b.0 - a.0).powi(2) + (b.1 - a.1).powi(2)).sqrt();
+    let steps = ((length / half_width).ceil() as usize).max(2);
+    let samples = (0..=steps)
+        .map(|i| {
+            let t = i as f32 / steps as f32;
+            let p = (a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t);
+            below.unsupported(p, half_width)
+        })
+        .collect::<Vec<f32>>();
+    let anchored = samples[0] < 1.0 && samples[steps] < 1.0;
+    if anchored && samples.iter().any(|u| *u >= 1.0) {
+        return Support::Bridge;
+    }
+    let percent = samples.iter().sum::<f32>() / samples.len() as f32 * 100.0;
+    if percent < OVERHANG_THRESHOLD {
+        Support::Supported
+    } else {
+        Support::Overhang(percent)
+    }
+}
+
+impl Parsed {
+    // support is worked out from the moves like the labels are, so setting it isn't an edit
+    // and goes around the undo history
+    pub fn classify_support(&mut self) {
+        let beads = self.beads();
+        let layers = self.layers();
+        if layers.is_empty() {
+            return;
+        }
+        let mut by_layer: Vec<Vec<Id>> = vec![Vec::new(); layers.len()];
+        for line in &self.lines {
+            if let Some(v) = self.vertices.get(line) {
+                if v.extrusion_move() {
+                    let i = layers
+                        .partition_point(|l| *l < v.to.z - f32::EPSILON)
+                        .min(layers.len() - 1);
+                    by_layer[i].push(*line);
+                }
+            }
+        }
+        let mut below: Option<PrintedMap> = None;
+        let mut out = Vec::new();
+        for layer in by_layer {
+            let mut printed = PrintedMap::new(2.0);
+            for id in layer {
+                let v = self.vertices.get(&id).unwrap();
+                let from = v.get_from(self);
+                let (a, b) = ((from.x, from.y), (v.to.x, v.to.y));
+                let half_width = beads.get(&id).map_or(0.2, |b| b.width / 2.0);
+                // the first layer sits on the bed
+                let support = below.as_ref().map_or(Support::Supported, |below| {
+                    classify(below, a, b, half_width)
+                });
+                out.push((id, support));
+                printed.add(a, b, half_width, v.to.z);
+            }
+            below = Some(printed);
+        }
+        for (id, support) in out {
+            self.vertices.get_mut(&id).unwrap().support = support;
+        }
+    }
+    fn fan_instruction(&mut self, speed: f32) -> Id {
+        let ins = if speed > 0.0 {
+            Instruction::new(Word('M', 106.0, None), vec![Word('S', speed, None)])
+        } else {
+            Instruction::new(Word('M', 107.0, None), Vec::new())
+        };
+        self.add_instruction(ins)
+    }
+    // caps the feedrate of overhangs and bridges at max_speed (mm/min), and optionally runs the
+    // part fan at a set speed (0-255) for them
+    // uses the classification from classify_support
+    // running it again changes nothing, moves already at or below max_speed keep their speed and
+    // the fan is only set around runs where it isn't already at that speed
+    pub fn slow_unsupported(&mut self, max_speed: f32, fan: Option<f32>) {
+        let mut lines = Vec::with_capacity(self.lines.len());
+        let mut fan_speed = 0.0;
+        let mut in_run = false;
+        let mut raised = false;
+        for line in self.lines.clone() {
+            if let Some(ins) = self.instructions.get(&line) {
+                let Word(letter, number, _) = ins.first_word;
+                match (letter, number.round() as i32) {
+                    ('M', 106) => {
+                        fan_speed = ins
+                            .params
+                            .iter()
+                            .flatten()
+                            .find(|w| w.0 == 'S')
+                            .map_or(255.0, |w| w.1);
+                    }
+                    ('M', 107) => fan_speed = 0.0,
+                    _ => (),
+                }
+                lines.push(line);
+                continue;
+            }
+            let Some(unsupported) = self.vertices.get(&line).map(|v| v.unsupported()) else {
+                lines.push(line);
+                continue;
+            };
+            if unsupported && self.vertices[&line].to.f > max_speed {
+                self.vertex_mut(&line).unwrap().to.f = max_speed;
+            }
+            if let Some(fan) = fan {
+                if unsupported && !in_run {
+                    raised = fan_speed != fan;
+                    if raised {
+                        lines.push(self.fan_instruction(fan));
+                    }
+                } else if !unsupported && in_run && raised {
+                    lines.push(self.fan_instruction(fan_speed));
+                }
+            }
+            in_run = unsupported;
+            lines.push(line);
+        }
+        if in_run && raised {
+            lines.push(self.fan_instruction(fan_speed));
+        }
+        *self.lines_mut() = lines;
+    }
+}
+
+#[cfg(test)]
+const TWO_LAYERS: &str = "G28\nG1 X10 Y10 Z0.2 F1200\nG1 Y20 E1\nG1 X30 Y10\nG1 Y20 E1\nG1 X10 Y15 Z0.4\nG1 X30 E2\nG1 X40 E1\nG1 X30 Y12\nG1 Y18 E0.3";
+
+#[test]
+fn support_test() {
+    let mut gcode = super::read(TWO_LAYERS, true).expect("failed to parse");
+    gcode.classify_support();
+    let support = |i: usize| gcode.vertices.get(&gcode.lines[i]).unwrap().support;
+    assert_eq!(support(2), Support::Supported);
+    assert_eq!(support(6), Support::Bridge);
+    assert!(matches!(support(7), Support::Overhang(p) if p > 50.0));
+    assert_eq!(support(9), Support::Supported);
+    assert_eq!(support(3), Support::Unknown);
+}
+
+#[test]
+fn slow_unsupported_test() {
+    use super::emit::Emit;
+    let mut gcode = super::read(TWO_LAYERS, true).expect("failed to parse");
+    gcode.classify_support();
+    gcode.slow_unsupported(600.0, Some(255.0));
+    let out = gcode.emit(&gcode, false);
+    let lines = out.lines().collect::<Vec<_>>();
+    let bridge = lines
+        .iter()
+        .position(|l| l.starts_with("G1 X30 E2"))
+        .unwrap();
+    assert_eq!(lines[bridge - 1], "M106 S255");
+    assert!(lines[bridge].contains("F600"));
+    assert_eq!(lines[bridge + 2], "M107");
+    // slowing again finds the moves already slow and the fan already on
+    gcode.classify_support();
+    gcode.slow_unsupported(600.0, Some(255.0));
+    assert_eq!(gcode.emit(&gcode, false), out);
+}
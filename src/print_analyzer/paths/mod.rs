@@ -0,0 +1,95 @@
+use super::{Id, Parsed};
+
+// start and end of a path closer than this make it a closed loop
+pub const CLOSED_TOLERANCE: f32 = 0.5;
+
+// an unbroken run of extrusion moves within a shape
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExtrusionPath {
+    pub shape: Id,
+    // the move the path starts from, i.e. the last move before the first extrusion
+    pub start: Id,
+    pub vertices: Vec<Id>,
+    pub layer: f32,
+}
+
+impl ExtrusionPath {
+    // xy of the start followed by the end of every extrusion
+    pub fn points(&self, gcode: &Parsed) -> Vec<(f32, f32)> {
+        std::iter::once(&self.start)
+            .chain(self.vertices.iter())
+            .map(|id| {
+                let v = gcode.vertices.get(id).unwrap();
+                (v.to.x, v.to.y)
+            })
+            .collect()
+    }
+    pub fn length(&self, gcode: &Parsed) -> f32 {
+        self.vertices
+            .iter()
+            .map(|id| gcode.dist_from_prev(id))
+            .sum()
+    }
+    pub fn is_closed(&self, gcode: &Parsed) -> bool {
+        if self.vertices.len() < 3 || self.length(gcode) < CLOSED_TOLERANCE * 4.0 {
+            return false;
+        }
+        let start = gcode.vertices.get(&self.start).unwrap().to;
+        let end = gcode
+            .vertices
+            .get(self.vertices.last().unwrap())
+            .unwrap()
+            .to;
+        start.dist(&end) < CLOSED_TOLERANCE
+    }
+}
+
+impl Parsed {
+    // every extrusion path in print order
+    pub fn extrusion_paths(&self) -> Vec<ExtrusionPath> {
+        let mut out = Vec::new();
+        for shape in &self.shapes {
+            let mut current: Option<ExtrusionPath> = None;
+            for line in &shape.lines {
+                // instructions between extrusions don't break a path
+                let Some(v) = self.vertices.get(line) else {
+                    continue;
+                };
+                if !v.extrusion_move() {
+                    out.extend(current.take());
+                    continue;
+                }
+                if let Some(path) = current.as_mut() {
+                    path.vertices.push(v.id);
+                } else if let Some(start) = v.prev {
+                    current = Some(ExtrusionPath {
+                        shape: shape.id,
+                        start,
+                        vertices: vec![v.id],
+                        layer: v.to.z,
+                    });
+                }
+            }
+            out.extend(current);
+        }
+        out
+    }
+    pub fn get_path(&self, vertex: &Id) -> Option<ExtrusionPath> {
+        self.extrusion_paths()
+            .into_iter()
+            .find(|p| p.start == *vertex || p.vertices.contains(vertex))
+    }
+}
+
+#[test]
+fn paths_test() {
+    let gcode = "G28\nG1 X10 Y10 Z0.2 F1200\nG1 X20 E1\nG1 Y20 E1\nG1 X10 E1\nG1 Y10 E1\nG1 X30\nG1 X40 E1\nM117 hi\nG1 X50 E1";
+    let gcode = super::read(gcode, true).expect("failed to parse");
+    let paths = gcode.extrusion_paths();
+    assert_eq!(paths.len(), 2);
+    assert!(paths[0].is_closed(&gcode));
+    assert_eq!(paths[0].points(&gcode)[0], (10.0, 10.0));
+    assert_eq!(paths[1].vertices.len(), 2);
+    assert!(!paths[1].is_closed(&gcode));
+    assert_eq!(gcode.get_path(&gcode.lines[9]), Some(paths[1].clone()));
+}
@@ -0,0 +1,128 @@
+use super::{edit::Feature, Id, Parsed, Pos};
+use std::{collections::HashSet, error::Error};
+
+// what gets inserted to stop the print, e.g. for a filament change
+#[derive(Clone, Debug, PartialEq)]
+pub struct PauseTemplate {
+    // sent once the nozzle is parked, M600, PAUSE, M0 or any macro
+    pub command: String,
+    // mm of filament, in mm/s
+    pub retract: f32,
+    pub retract_speed: f32,
+    pub z_lift: f32,
+    // where to park, or pause in place
+    pub park: Option<(f32, f32)>,
+    // mm/min for the moves to and from the park position
+    pub travel_speed: f32,
+}
+
+impl PauseTemplate {
+    // the pause with the nozzle at `at`, ending back there with the filament where it was
+    pub fn gcode(&self, at: &Pos) -> String {
+        let retract_f = self.retract_speed * 60.0;
+        let mut out = String::new();
+        if self.retract > 0.0 {
+            out += &format!("G1 E{} F{}\n", -self.retract, retract_f);
+        }
+        if self.z_lift > 0.0 {
+            out += &format!("G1 Z{} F{}\n", at.z + self.z_lift, self.travel_speed);
+        }
+        if let Some((x, y)) = self.park {
+            out += &format!("G1 X{} Y{} F{}\n", x, y, self.travel_speed);
+        }
+        out += &self.command;
+        out += "\n";
+        if self.park.is_some() {
+            out += &format!("G1 X{} Y{} F{}\n", at.x, at.y, self.travel_speed);
+        }
+        if self.z_lift > 0.0 {
+            out += &format!("G1 Z{} F{}\n", at.z, self.travel_speed);
+        }
+        if self.retract > 0.0 {
+            out += &format!("G1 E{} F{}\n", self.retract, retract_f);
+        }
+        out
+    }
+}
+
+impl Parsed {
+    fn layer_start(&self, z: f32) -> Option<Id> {
+        self.layer_starts()
+            .into_iter()
+            .find(|(layer, _)| (layer - z).abs() < f32::EPSILON)
+            .map(|(_, id)| id)
+    }
+    // pauses right before the print moves up to the layer at z, returns the new line ids
+    pub fn insert_pause(
+        &mut self,
+        z: f32,
+        template: &PauseTemplate,
+    ) -> Result<Vec<Id>, Box<dyn Error>> {
+        let start = self.layer_start(z).ok_or("no layer at that height")?;
+        let at = self.vertices.get(&start).unwrap().get_from(self);
+        self.insert_before(
+            &Feature::Vertices(HashSet::from([start])),
+            &template.gcode(&at),
+        )
+    }
+}
+
+#[test]
+fn pause_test() {
+    use super::{emit::Emit, Label};
+    let gcode = "G28\nG1 X10 Y10 Z0.2 F1200\nG1 X20 E1\nG1 Z0.4\nG1 X10 E1";
+    let mut gcode = super::read(gcode, true).expect("failed to parse");
+    let original = gcode.lines.clone();
+    let template = PauseTemplate {
+        command: String::from("M600"),
+        retract: 5.0,
+        retract_speed: 40.0,
+        z_lift: 10.0,
+        park: Some((0.0, 200.0)),
+        travel_speed: 6000.0,
+    };
+    let new = gcode
+        .insert_pause(0.4, &template)
+        .expect("failed to insert");
+    assert_eq!(new.len(), 7);
+    assert_eq!(&gcode.lines[3..10], &new[..]);
+    let labels = new
+        .iter()
+        .filter_map(|id| gcode.vertices.get(id))
+        .map(|v| v.label)
+        .collect::<Vec<Label>>();
+    assert_eq!(
+        labels,
+        [
+            Label::Retraction,
+            Label::LiftZ,
+            Label::TravelMove,
+            Label::TravelMove,
+            Label::LowerZ,
+            Label::DeRetraction
+        ]
+    );
+    // back where the layer change started, with no net filament moved
+    let back = gcode.vertices.get(&new[6]).unwrap().to;
+    assert_eq!((back.x, back.y, back.z), (20.0, 10.0, 0.2));
+    assert_eq!(gcode.vertices.values().map(|v| v.to.e).sum::<f32>(), 2.0);
+    let lift = gcode.vertices.get(&original[3]).unwrap();
+    assert_eq!(lift.prev, Some(new[6]));
+    assert_eq!(lift.label, Label::LiftZ);
+    let out = gcode.emit(&gcode, false);
+    assert!(out.contains("M600\nG1 X20 Y10 \nG1 Z0.2 \nG1 E5 F2400 \nG1 Z0.4 F1200 \n"));
+    assert!(gcode.insert_pause(3.0, &template).is_err());
+    // pausing in place with a firmware macro
+    let template = PauseTemplate {
+        command: String::from("PAUSE"),
+        retract: 0.0,
+        z_lift: 0.0,
+        park: None,
+        ..template
+    };
+    let new = gcode
+        .insert_pause(0.2, &template)
+        .expect("failed to insert");
+    assert_eq!(new.len(), 1);
+    assert!(gcode.instructions.contains_key(&new[0]));
+}
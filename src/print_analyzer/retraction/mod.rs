@@ -0,0 +1,535 @@
+use super::{Id, Instruction, Label, Parsed, Pos, Word};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Summary {
+    pub count: usize,
+    pub total: f32,
+    pub min: f32,
+    pub max: f32,
+}
+
+impl Default for Summary {
+    fn default() -> Self {
+        Summary {
+            count: 0,
+            total: 0.0,
+            min: f32::INFINITY,
+            max: f32::NEG_INFINITY,
+        }
+    }
+}
+
+impl Summary {
+    fn add(&mut self, value: f32) {
+        self.count += 1;
+        self.total += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+    pub fn mean(&self) -> f32 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.total / self.count as f32
+        }
+    }
+    pub fn to_json(self) -> Value {
+        if self.count == 0 {
+            return json!({ "count": 0 });
+        }
+        json!({
+            "count": self.count,
+            "total": self.total,
+            "min": self.min,
+            "max": self.max,
+            "mean": self.mean(),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RetractionStats {
+    // filament pulled back, mm, including what is pulled back during wipes
+    pub retract: Summary,
+    // mm/s
+    pub retract_speed: Summary,
+    pub deretract: Summary,
+    pub deretract_speed: Summary,
+    // xy distance covered by wipe moves
+    pub wipe: Summary,
+    pub z_hop: Summary,
+    // filament pushed on unretract beyond what was pulled back
+    pub unretract_extra: Summary,
+    // xy distance travelled between a retraction and its unretract
+    pub travel: Summary,
+}
+
+impl RetractionStats {
+    pub fn to_json(self) -> Value {
+        json!({
+            "retract": self.retract.to_json(),
+            "retract_speed": self.retract_speed.to_json(),
+            "deretract": self.deretract.to_json(),
+            "deretract_speed": self.deretract_speed.to_json(),
+            "wipe": self.wipe.to_json(),
+            "z_hop": self.z_hop.to_json(),
+            "unretract_extra": self.unretract_extra.to_json(),
+            "travel": self.travel.to_json(),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RetractionReport {
+    pub total: RetractionStats,
+    // layer z and the stats of the retractions made while printing it
+    pub layers: Vec<(f32, RetractionStats)>,
+    pub shapes: Vec<(Id, RetractionStats)>,
+}
+
+impl RetractionReport {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "total": self.total.to_json(),
+            "layers": self
+                .layers
+                .iter()
+                .map(|(z, stats)| json!({ "z": z, "stats": stats.to_json() }))
+                .collect::<Vec<_>>(),
+            "shapes": self
+                .shapes
+                .iter()
+                .map(|(id, stats)| json!({ "shape": id.0, "stats": stats.to_json() }))
+                .collect::<Vec<_>>(),
+        })
+    }
+}
+
+// a retraction waiting for its unretract
+struct Pending {
+    retracted: f32,
+    travel: f32,
+    layer: usize,
+    shape: Option<usize>,
+}
+
+impl Parsed {
+    pub fn retraction_report(&self) -> RetractionReport {
+        let mut shape_of = HashMap::new();
+        for (i, shape) in self.shapes.iter().enumerate() {
+            for line in &shape.lines {
+                shape_of.insert(*line, i);
+            }
+        }
+        let mut layers: Vec<(f32, RetractionStats)> = Vec::new();
+        let mut shapes = vec![RetractionStats::default(); self.shapes.len()];
+        let mut total = RetractionStats::default();
+        let mut layer_z = 0.0;
+        let mut pending: Option<Pending> = None;
+        // the shape of the last extrusion, which the retractions after it belong to
+        let mut last_shape = None;
+
+        for line in &self.lines {
+            let Some(v) = self.vertices.get(line) else {
+                continue;
+            };
+            if v.label == Label::PlanarExtrustion && v.to.z != layer_z {
+                layer_z = v.to.z;
+            }
+            if layers.last().map(|(z, _)| *z) != Some(layer_z) {
+                layers.push((layer_z, RetractionStats::default()));
+            }
+            let layer = layers.len() - 1;
+            // change moves split shapes, so they count towards the shape they end
+            if v.extrusion_move() {
+                last_shape = shape_of.get(line).copied();
+            }
+            let shape = last_shape;
+            let from = v.get_from(self);
+            let xy = ((v.to.x - from.x).powi(2) + (v.to.y - from.y).powi(2)).sqrt();
+            let speed = v.to.f / 60.0;
+            let mut record =
+                |layer: usize, shape: Option<usize>, f: &dyn Fn(&mut RetractionStats)| {
+                    f(&mut total);
+                    f(&mut layers[layer].1);
+                    if let Some(shape) = shape {
+                        f(&mut shapes[shape]);
+                    }
+                };
+            let retracted = -v.to.e;
+            match v.label {
+                Label::Retraction | Label::Wipe | Label::LiftZ if retracted > 0.0 => {
+                    record(layer, shape, &|s| {
+                        s.retract.add(retracted);
+                        s.retract_speed.add(speed);
+                    });
+                    if v.label == Label::Wipe {
+                        record(layer, shape, &|s| s.wipe.add(xy));
+                    }
+                    let p = pending.get_or_insert(Pending {
+                        retracted: 0.0,
+                        travel: 0.0,
+                        layer,
+                        shape,
+                    });
+                    p.retracted += retracted;
+                }
+                Label::DeRetraction => {
+                    let amount = v.to.e;
+                    record(layer, shape, &|s| {
+                        s.deretract.add(amount);
+                        s.deretract_speed.add(speed);
+                    });
+                    if let Some(p) = pending.take() {
+                        record(p.layer, p.shape, &|s| {
+                            s.unretract_extra.add(amount - p.retracted);
+                            s.travel.add(p.travel);
+                        });
+                    }
+                }
+                Label::TravelMove => {
+                    if let Some(p) = pending.as_mut() {
+                        p.travel += xy;
+                    }
+                }
+                _ => (),
+            }
+            if v.label == Label::LiftZ {
+                let height = v.to.z - from.z;
+                record(layer, shape, &|s| s.z_hop.add(height));
+            }
+        }
+        RetractionReport {
+            total,
+            layers,
+            shapes: self.shapes.iter().map(|s| s.id).zip(shapes).collect(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetractParams {
+    // travels at least this long in xy get a retraction, shorter ones lose theirs
+    pub min_travel: f32,
+    // mm of filament
+    pub length: f32,
+    // mm/s for the retract and the unretract
+    pub speed: f32,
+    // filament pushed on unretract on top of what was pulled back
+    pub extra_restart: f32,
+    // G10 and G11 instead of E moves, the firmware sets the length and speed
+    pub firmware: bool,
+}
+
+// Some(true) for a firmware retract, Some(false) for a firmware unretract
+fn firmware_retraction(ins: &Instruction) -> Option<bool> {
+    match (ins.first_word.0, ins.first_word.1.round() as i32) {
+        ('G', 10) => Some(true),
+        ('G', 11) => Some(false),
+        _ => None,
+    }
+}
+
+impl Parsed {
+    // the lines between one extrusion and the next as (first, next extrusion) indices
+    // with edges the start and end gcode are gaps too, from 0 and up to the end of the file,
+    // otherwise they aren't between extrusions
+    fn gaps(&self, edges: bool) -> Vec<(usize, usize)> {
+        let mut out = Vec::new();
+        let mut last: Option<usize> = None;
+        for (i, id) in self.lines.iter().enumerate() {
+            if !self.vertices.get(id).is_some_and(|v| v.extrusion_move()) {
+                continue;
+            }
+            match last {
+                Some(last) if i > last + 1 => out.push((last + 1, i)),
+                None if edges && i > 0 => out.push((0, i)),
+                _ => (),
+            }
+            last = Some(i);
+        }
+        match last {
+            Some(last) if edges && last + 1 < self.lines.len() => {
+                out.push((last + 1, self.lines.len()))
+            }
+            None if edges => out.push((0, self.lines.len())),
+            _ => (),
+        }
+        out
+    }
+    // retracts over gaps travelling at least min_travel that have no retraction and strips
+    // plain retractions from shorter ones, with forced only the gaps holding one of its
+    // lines are looked at and they always get a retraction
+    // returns how many gaps got a retraction and how many lost theirs
+    fn edit_retractions(
+        &mut self,
+        params: &RetractParams,
+        forced: Option<&HashSet<Id>>,
+    ) -> (usize, usize) {
+        let f = params.speed * 60.0;
+        let mut inserts: HashMap<usize, Vec<Id>> = HashMap::new();
+        let mut removed: HashSet<Id> = HashSet::new();
+        let (mut added, mut stripped) = (0, 0);
+        for (mut start, end) in self.gaps(forced.is_some()) {
+            if let Some(ids) = forced {
+                let Some(first) = self.lines[start..end]
+                    .iter()
+                    .position(|id| ids.contains(id))
+                else {
+                    continue;
+                };
+                // before the first extrusion the retraction goes right before the hole
+                if start == 0 {
+                    start = first;
+                }
+            }
+            let gap = &self.lines[start..end];
+            let mut length = 0.0;
+            let mut retracted = false;
+            // only bare retracts and unretracts are taken out, not wipes or hops that retract
+            let mut plain = true;
+            for id in gap {
+                if let Some(v) = self.vertices.get(id) {
+                    let from = v.get_from(self);
+                    length += ((v.to.x - from.x).powi(2) + (v.to.y - from.y).powi(2)).sqrt();
+                    retracted |= v.to.e < 0.0;
+                    plain &= v.to.e == 0.0
+                        || v.label == Label::Retraction
+                        || v.label == Label::DeRetraction;
+                } else if let Some(retract) = firmware_retraction(&self.instructions[id]) {
+                    retracted |= retract;
+                }
+            }
+            let wanted = forced.is_some() || length >= params.min_travel;
+            if wanted && !retracted && (params.firmware || params.length > 0.0) {
+                let from = gap.iter().find_map(|id| self.vertices.get(id)).map_or_else(
+                    || self.vertices[&self.lines[start - 1]].to,
+                    |v| v.get_from(self),
+                );
+                // nothing is printed after the last gap, so it isn't unretracted
+                let to = self
+                    .lines
+                    .get(end)
+                    .map(|id| self.vertices[id].get_from(self));
+                let (retract, mut unretract) = if params.firmware {
+                    (
+                        vec![self.add_instruction(Instruction::new(Word('G', 10.0, None), vec![]))],
+                        to.iter()
+                            .map(|_| {
+                                self.add_instruction(Instruction::new(
+                                    Word('G', 11.0, None),
+                                    vec![],
+                                ))
+                            })
+                            .collect(),
+                    )
+                } else {
+                    (
+                        vec![self.add_vertex(Pos {
+                            e: -params.length,
+                            f,
+                            ..from
+                        })],
+                        Vec::new(),
+                    )
+                };
+                let push = if params.firmware {
+                    params.extra_restart
+                } else {
+                    params.length + params.extra_restart
+                };
+                if let Some(to) = to.filter(|_| push > 0.0) {
+                    unretract.push(self.add_vertex(Pos { e: push, f, ..to }));
+                }
+                inserts.insert(start, retract);
+                inserts.insert(end, unretract);
+                added += 1;
+            } else if !wanted && retracted && plain {
+                let gap = &self.lines[start..end];
+                removed.extend(gap.iter().copied().filter(|id| {
+                    self.vertices.get(id).is_some_and(|v| v.to.e != 0.0)
+                        || self
+                            .instructions
+                            .get(id)
+                            .is_some_and(|ins| firmware_retraction(ins).is_some())
+                }));
+                stripped += 1;
+            }
+        }
+        let mut lines = Vec::with_capacity(self.lines.len() + added * 2);
+        for (i, line) in self.lines.iter().enumerate() {
+            lines.extend(inserts.get(&i).into_iter().flatten());
+            if !removed.contains(line) {
+                lines.push(*line);
+            }
+        }
+        *self.lines_mut() = lines;
+        for line in &removed {
+            self.remove_vertex(line);
+            self.remove_instruction(line);
+        }
+        self.set_counts();
+        for id in inserts.values().flatten() {
+            if self.vertices.contains_key(id) {
+                self.relabel(id);
+            }
+        }
+        self.assign_shapes();
+        (added, stripped)
+    }
+    // returns how many travels got a retraction and how many lost theirs
+    pub fn set_retractions(&mut self, params: &RetractParams) -> (usize, usize) {
+        self.edit_retractions(params, None)
+    }
+    // retracts around the travels whatever their length, returns how many retractions were added
+    pub fn retract_travels(&mut self, travels: &HashSet<Id>, params: &RetractParams) -> usize {
+        self.edit_retractions(params, Some(travels)).0
+    }
+}
+
+#[test]
+fn retraction_test() {
+    let gcode = "G28\nG1 X10 Y10 Z0.2 F3000\nG1 X20 E1 F1200\nG1 E-0.8 F2400\nG1 Z0.6 F600\nG1 X30 F6000\nG1 X40\nG1 Z0.2 F600\nG1 E0.9 F2400\nG1 X50 E1 F1200";
+    let gcode = super::read(gcode, true).expect("failed to parse");
+    let report = gcode.retraction_report();
+    let total = report.total;
+    assert_eq!(total.retract.count, 1);
+    assert!((total.retract.total - 0.8).abs() < 1e-5);
+    assert!((total.retract_speed.max - 40.0).abs() < 1e-5);
+    assert_eq!(total.deretract.count, 1);
+    assert!((total.z_hop.max - 0.4).abs() < 1e-5);
+    assert!((total.unretract_extra.total - 0.1).abs() < 1e-5);
+    assert!((total.travel.total - 20.0).abs() < 1e-5);
+    assert_eq!(report.layers.last().unwrap().1.retract.count, 1);
+    // everything from the retraction to the unretract counts towards the line it ends
+    let printed = gcode
+        .shapes
+        .iter()
+        .find(|s| s.lines.contains(&gcode.lines[2]))
+        .unwrap()
+        .id;
+    for (id, stats) in &report.shapes {
+        if *id != printed {
+            assert_eq!(*stats, RetractionStats::default());
+            continue;
+        }
+        assert_eq!(stats.retract.count, 1);
+        assert!((stats.retract_speed.max - 40.0).abs() < 1e-5);
+        assert!((stats.z_hop.max - 0.4).abs() < 1e-5);
+        assert_eq!(stats.deretract.count, 1);
+        assert!((stats.unretract_extra.total - 0.1).abs() < 1e-5);
+        assert!((stats.travel.total - 20.0).abs() < 1e-5);
+    }
+    let json = report.to_json();
+    assert_eq!(json["total"]["retract"]["count"], 1);
+}
+
+#[test]
+fn set_retractions_test() {
+    use super::emit::Emit;
+    // a short travel, a short retracted one and a long one
+    let text = "G28\nG1 X10 Y10 Z0.2 F1200\nG1 X20 E1\nG1 X21\nG1 X22 E0.1\nG1 E-0.8 F2400\nG1 X23 F6000\nG1 E0.8 F2400\nG1 X24 E0.1 F1200\nG1 X40 F6000\nG1 X50 E1 F1200";
+    let mut gcode = super::read(text, true).expect("failed to parse");
+    let mut params = RetractParams {
+        min_travel: 2.0,
+        length: 0.5,
+        speed: 30.0,
+        extra_restart: 0.1,
+        firmware: false,
+    };
+    assert_eq!(gcode.set_retractions(&params), (1, 1));
+    assert_eq!(gcode.lines.len(), 11);
+    let out = gcode.emit(&gcode, false);
+    assert!(!out.contains("E-0.8"));
+    assert!(out.contains("G1 X24 E0.1 F1200 \nG1 E-0.5 F1800 \nG1 X40 F6000 \nG1 E"));
+    let labels = gcode.lines[7..11]
+        .iter()
+        .map(|id| gcode.vertices[id].label)
+        .collect::<Vec<Label>>();
+    assert_eq!(
+        labels,
+        [
+            Label::Retraction,
+            Label::TravelMove,
+            Label::DeRetraction,
+            Label::PlanarExtrustion
+        ]
+    );
+    let unretract = gcode.vertices[&gcode.lines[9]].to;
+    assert!((unretract.e - 0.6).abs() < 1e-5);
+    assert_eq!((unretract.x, unretract.f), (40.0, 1800.0));
+    assert_eq!(gcode.set_retractions(&params), (0, 0));
+    // firmware retraction on every travel, then taken off all of them
+    let mut gcode = super::read(text, true).expect("failed to parse");
+    params.min_travel = 0.5;
+    params.firmware = true;
+    params.extra_restart = 0.0;
+    assert_eq!(gcode.set_retractions(&params), (2, 0));
+    let out = gcode.emit(&gcode, false);
+    assert_eq!(out.matches("G10").count(), 2);
+    assert!(out.contains("G1 X20 E1 \nG10\nG1 X21 \nG11\n"));
+    params.min_travel = 20.0;
+    assert_eq!(gcode.set_retractions(&params), (0, 3));
+    assert!(gcode.instructions.is_empty());
+    assert_eq!(gcode.retraction_report().total.retract.count, 0);
+    // holes get retracted however short they are
+    let mut gcode = super::read(
+        "G28\nG1 X10 Y10 Z0.2 F1200\nG1 X11 E0.1\nG1 X20 E1\nG1 X30 E1",
+        true,
+    )
+    .expect("failed to parse");
+    params.firmware = false;
+    let mut hole = HashSet::from([gcode.lines[3]]);
+    gcode.hole_delete(&mut hole, &params);
+    let labels = gcode
+        .lines
+        .iter()
+        .map(|id| gcode.vertices[id].label)
+        .collect::<Vec<Label>>();
+    assert_eq!(
+        labels[2..],
+        [
+            Label::PlanarExtrustion,
+            Label::Retraction,
+            Label::TravelMove,
+            Label::DeRetraction,
+            Label::PlanarExtrustion
+        ]
+    );
+    // and so do holes in the first and last extrusion, nothing comes after the last to unretract
+    let text = "G28\nG1 X10 Y10 Z0.2 F1200\nG1 X11 E0.1\nG1 X20 E1\nG1 X30 E1";
+    let labels = |gcode: &Parsed| {
+        gcode
+            .lines
+            .iter()
+            .map(|id| gcode.vertices[id].label)
+            .collect::<Vec<Label>>()
+    };
+    let mut gcode = super::read(text, true).expect("failed to parse");
+    let mut hole = HashSet::from([gcode.lines[2]]);
+    gcode.hole_delete(&mut hole, &params);
+    assert_eq!(
+        labels(&gcode)[2..],
+        [
+            Label::Retraction,
+            Label::TravelMove,
+            Label::DeRetraction,
+            Label::PlanarExtrustion,
+            Label::PlanarExtrustion
+        ]
+    );
+    let mut gcode = super::read(text, true).expect("failed to parse");
+    let mut hole = HashSet::from([gcode.lines[4]]);
+    gcode.hole_delete(&mut hole, &params);
+    assert_eq!(
+        labels(&gcode)[2..],
+        [
+            Label::PlanarExtrustion,
+            Label::PlanarExtrustion,
+            Label::Retraction,
+            Label::TravelMove
+        ]
+    );
+}
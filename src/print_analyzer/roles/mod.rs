@@ -0,0 +1,260 @@
+use super::{
+    bounds::point_in_polygon, collision::PrintedMap, paths::ExtrusionPath, Id, Parsed, Role,
+};
+use std::collections::{HashMap, HashSet};
+
+type Point = (f32, f32);
+
+// mm, the side of the cells layer points are bucketed in
+const GRID_CELL: f32 = 5.0;
+
+fn bbox(points: &[Point]) -> (f32, f32, f32, f32) {
+    points.iter().fold(
+        (
+            f32::INFINITY,
+            f32::INFINITY,
+            f32::NEG_INFINITY,
+            f32::NEG_INFINITY,
+        ),
+        |(x0, y0, x1, y1), (x, y)| (x0.min(*x), y0.min(*y), x1.max(*x), y1.max(*y)),
+    )
+}
+
+fn bbox_contains(outer: (f32, f32, f32, f32), inner: (f32, f32, f32, f32)) -> bool {
+    outer.0 <= inner.0 && outer.1 <= inner.1 && outer.2 >= inner.2 && outer.3 >= inner.3
+}
+
+fn dist_to_polyline(p: Point, line: &[Point]) -> f32 {
+    line.windows(2)
+        .map(|seg| {
+            let (a, b) = (seg[0], seg[1]);
+            let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+            let len = dx * dx + dy * dy;
+            let t = if len > 0.0 {
+                (((p.0 - a.0) * dx + (p.1 - a.1) * dy) / len).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+            ((p.0 - a.0 - t * dx).powi(2) + (p.1 - a.1 - t * dy).powi(2)).sqrt()
+        })
+        .fold(f32::INFINITY, f32::min)
+}
+
+// the layer's points bucketed by grid cell, so a loop only looks at the points under it
+struct PointGrid {
+    cell: f32,
+    cells: HashMap<(i32, i32), Vec<Point>>,
+}
+
+impl PointGrid {
+    fn new(cell: f32, points: &[Point]) -> PointGrid {
+        let mut grid = PointGrid {
+            cell,
+            cells: HashMap::new(),
+        };
+        for p in points {
+            grid.cells.entry(grid.key(*p)).or_default().push(*p);
+        }
+        grid
+    }
+    fn key(&self, p: Point) -> (i32, i32) {
+        (
+            (p.0 / self.cell).floor() as i32,
+            (p.1 / self.cell).floor() as i32,
+        )
+    }
+    // points strictly inside the box
+    fn inside(&self, bbox: (f32, f32, f32, f32)) -> impl Iterator<Item = &Point> {
+        let (lo, hi) = (self.key((bbox.0, bbox.1)), self.key((bbox.2, bbox.3)));
+        (lo.0..=hi.0)
+            .flat_map(move |i| (lo.1..=hi.1).map(move |j| (i, j)))
+            .filter_map(|key| self.cells.get(&key))
+            .flatten()
+            .filter(move |p| p.0 > bbox.0 && p.1 > bbox.1 && p.0 < bbox.2 && p.1 < bbox.3)
+    }
+}
+
+struct Loop {
+    path: usize,
+    points: Vec<Point>,
+    bbox: (f32, f32, f32, f32),
+    width: f32,
+}
+
+// tells outer from inner perimeters among the closed loops of one layer
+// a loop is an outer perimeter if it has no loop right outside it, or if nothing is printed
+// inside it (the wall around a hole); thin parts with no infill between walls can fool this
+fn perimeter_roles(loops: &[Loop], layer_points: &[Point]) -> Vec<Role> {
+    let grid = PointGrid::new(GRID_CELL, layer_points);
+    loops
+        .iter()
+        .enumerate()
+        .map(|(i, l)| {
+            let sample = l.points.iter().step_by((l.points.len() / 8).max(1));
+            let has_parent = loops.iter().enumerate().any(|(j, m)| {
+                j != i
+                    && bbox_contains(m.bbox, l.bbox)
+                    && point_in_polygon(&m.points, l.points[0].0, l.points[0].1)
+                    && sample
+                        .clone()
+                        .all(|p| dist_to_polyline(*p, &m.points) < l.width.max(m.width) * 1.5)
+            });
+            if !has_parent {
+                return Role::OuterPerimeter;
+            }
+            let filled = grid.inside(l.bbox).any(|p| {
+                dist_to_polyline(*p, &l.points) > l.width / 2.0
+                    && point_in_polygon(&l.points, p.0, p.1)
+            });
+            if filled {
+                Role::InnerPerimeter
+            } else {
+                Role::OuterPerimeter
+            }
+        })
+        .collect()
+}
+
+impl Parsed {
+    // the extrusions of the open paths laid down before the first loop, which classify_roles
+    // calls purging, worked out from the moves as they are now rather than as they were read
+    pub fn purge_moves(&self) -> HashSet<Id> {
+        let paths = self.extrusion_paths();
+        let first_loop = paths.iter().position(|p| p.is_closed(self)).unwrap_or(0);
+        paths[..first_loop]
+            .iter()
+            .flat_map(|p| p.vertices.iter().copied())
+            .collect()
+    }
+    // infers what each extrusion path is from its geometry, for files without slicer annotations
+    // run once when the file is read, edits don't reclassify so their moves keep the role they
+    // had, or unknown for new ones
+    pub fn classify_roles(&mut self) {
+        let paths = self.extrusion_paths();
+        let layers = self.layers();
+        if paths.is_empty() || layers.is_empty() {
+            return;
+        }
+        let beads = self.beads();
+        let width = |path: &ExtrusionPath| {
+            let widths = path
+                .vertices
+                .iter()
+                .filter_map(|id| beads.get(id).map(|b| b.width))
+                .collect::<Vec<f32>>();
+            if widths.is_empty() {
+                0.4
+            } else {
+                widths.iter().sum::<f32>() / widths.len() as f32
+            }
+        };
+        let layer_of = |path: &ExtrusionPath| {
+            layers
+                .partition_point(|l| *l < path.layer - f32::EPSILON)
+                .min(layers.len() - 1)
+        };
+        let closed = paths
+            .iter()
+            .map(|p| p.is_closed(self))
+            .collect::<Vec<bool>>();
+        let mut roles = vec![Role::Unknown; paths.len()];
+
+        // open lines laid down before the first loop are priming the nozzle
+        if let Some(first_loop) = closed.iter().position(|c| *c) {
+            for role in roles.iter_mut().take(first_loop) {
+                *role = Role::Purge;
+            }
+        }
+
+        // first layer paths with nothing printed on top of them are skirts or brims
+        if layers.len() > 1 {
+            let mut second = PrintedMap::new(2.0);
+            for path in paths.iter().filter(|p| layer_of(p) == 1) {
+                let points = path.points(self);
+                for seg in points.windows(2) {
+                    second.add(seg[0], seg[1], width(path) / 2.0, path.layer);
+                }
+            }
+            for (i, path) in paths.iter().enumerate() {
+                if roles[i] != Role::Unknown || layer_of(path) != 0 {
+                    continue;
+                }
+                let half_width = width(path) / 2.0;
+                if path
+                    .points(self)
+                    .iter()
+                    .all(|p| second.unsupported(*p, half_width) >= 1.0)
+                {
+                    roles[i] = Role::SkirtBrim;
+                }
+            }
+        }
+
+        // whatever is left is a perimeter if it's a loop and infill otherwise
+        let mut by_layer = vec![Vec::new(); layers.len()];
+        for (i, path) in paths.iter().enumerate() {
+            if roles[i] == Role::Unknown {
+                by_layer[layer_of(path)].push(i);
+            }
+        }
+        for layer in by_layer {
+            let mut loops = Vec::new();
+            let mut layer_points = Vec::new();
+            for i in layer {
+                let points = paths[i].points(self);
+                layer_points.extend(points.iter().copied());
+                if closed[i] {
+                    loops.push(Loop {
+                        path: i,
+                        bbox: bbox(&points),
+                        points,
+                        width: width(&paths[i]),
+                    });
+                } else {
+                    roles[i] = Role::Infill;
+                }
+            }
+            for (l, role) in loops.iter().zip(perimeter_roles(&loops, &layer_points)) {
+                roles[l.path] = role;
+            }
+        }
+
+        let mut role_of = HashMap::new();
+        for (path, role) in paths.iter().zip(roles) {
+            for id in &path.vertices {
+                role_of.insert(*id, role);
+            }
+        }
+        let ids = self.vertices.keys().copied().collect::<Vec<Id>>();
+        for id in ids {
+            let role = role_of.get(&id).copied().unwrap_or(Role::Unknown);
+            if self.vertices[&id].role != role {
+                self.vertex_mut(&id).unwrap().role = role;
+            }
+        }
+    }
+}
+
+#[test]
+fn roles_test() {
+    // purge line, skirt, then two layers of a square with two perimeters and a line of infill
+    let mut gcode = String::from("G28\nG1 X10 Y10 Z0.2 F1200\nG1 X60 E5\nG1 X20 Y20\nG1 X80 E3\nG1 Y80 E3\nG1 X20 E3\nG1 Y20 E3\n");
+    for z in ["0.2", "0.4"] {
+        gcode += &format!(
+            "G1 X30 Y30 Z{}\nG1 X70 E2\nG1 Y70 E2\nG1 X30 E2\nG1 Y30 E2\n",
+            z
+        );
+        gcode += "G1 X31 Y31\nG1 X69 E2\nG1 Y69 E2\nG1 X31 E2\nG1 Y31 E2\n";
+        gcode += "G1 X32 Y32\nG1 X68 Y68 E2\n";
+    }
+    gcode += "G1 Z1";
+    let gcode = super::read(&gcode, true).expect("failed to parse");
+    let role = |i: usize| gcode.vertices.get(&gcode.lines[i]).unwrap().role;
+    assert_eq!(role(2), Role::Purge);
+    assert_eq!(role(4), Role::SkirtBrim);
+    assert_eq!(role(9), Role::OuterPerimeter);
+    assert_eq!(role(14), Role::InnerPerimeter);
+    assert_eq!(role(19), Role::Infill);
+    assert_eq!(role(21), Role::OuterPerimeter);
+    assert_eq!(role(3), Role::Unknown);
+}
@@ -0,0 +1,357 @@
+use super::{Id, Instruction, Label, Parsed, Word};
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+};
+
+// a setting that calibration towers change between layers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Parameter {
+    // hotend temperature, M104 S
+    Temperature,
+    // part fan 0-255, M106 S
+    Fan,
+    // flow percentage, M221 S
+    Flow,
+    // speed percentage, M220 S
+    Speed,
+}
+
+impl Parameter {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Parameter::Temperature => "temperature",
+            Parameter::Fan => "fan",
+            Parameter::Flow => "flow",
+            Parameter::Speed => "speed",
+        }
+    }
+    // M109 when wait is set and the parameter is the temperature
+    fn instruction(&self, value: f32, wait: bool) -> Instruction {
+        let code = match self {
+            Parameter::Temperature if wait => 109.0,
+            Parameter::Temperature => 104.0,
+            Parameter::Fan => 106.0,
+            Parameter::Flow => 221.0,
+            Parameter::Speed => 220.0,
+        };
+        Instruction::new(Word('M', code, None), vec![Word('S', value, None)])
+    }
+    // what firmware starts with, put back after a schedule in files that never set it
+    fn default(&self) -> Option<f32> {
+        match self {
+            Parameter::Temperature => None,
+            Parameter::Fan => Some(0.0),
+            Parameter::Flow | Parameter::Speed => Some(100.0),
+        }
+    }
+    // the value an existing instruction sets, if it sets this parameter
+    fn value(&self, ins: &Instruction) -> Option<f32> {
+        let Word(letter, number, _) = ins.first_word;
+        let s = ins
+            .params
+            .iter()
+            .flatten()
+            .find(|w| w.0 == 'S')
+            .map(|w| w.1);
+        match (self, letter, number.round() as i32) {
+            (Parameter::Temperature, 'M', 104 | 109) => s,
+            (Parameter::Fan, 'M', 106) => Some(s.unwrap_or(255.0)),
+            (Parameter::Fan, 'M', 107) => Some(0.0),
+            (Parameter::Flow, 'M', 221) | (Parameter::Speed, 'M', 220) => s,
+            _ => None,
+        }
+    }
+}
+
+// the value a parameter should have at each layer, layers it doesn't cover are left alone
+#[derive(Clone, Debug, PartialEq)]
+pub enum Schedule {
+    // (first layer, last layer, value), layers counted from 0 and inclusive
+    Steps(Vec<(usize, usize, f32)>),
+    // linear from value.0 at z.0 to value.1 at z.1, rounded to whole numbers
+    Ramp { z: (f32, f32), value: (f32, f32) },
+}
+
+impl Schedule {
+    // reads steps written as "0-20: 215, 21-40: 210", a single layer is "5: 200"
+    pub fn parse_steps(text: &str) -> Result<Schedule, Box<dyn Error>> {
+        let mut steps = Vec::new();
+        for step in text
+            .split([',', ';', '\n'])
+            .filter(|s| !s.trim().is_empty())
+        {
+            let (layers, value) = step.split_once(':').ok_or("steps need a value after ':'")?;
+            let (first, last) = layers.split_once('-').unwrap_or((layers, layers));
+            let (first, last) = (first.trim().parse()?, last.trim().parse()?);
+            if first > last {
+                return Err("step ends before it starts".into());
+            }
+            steps.push((first, last, value.trim().parse()?));
+        }
+        if steps.is_empty() {
+            return Err("no steps".into());
+        }
+        Ok(Schedule::Steps(steps))
+    }
+    pub fn value(&self, layer: usize, z: f32) -> Option<f32> {
+        match self {
+            Schedule::Steps(steps) => steps
+                .iter()
+                .rev()
+                .find(|(first, last, _)| (*first..=*last).contains(&layer))
+                .map(|step| step.2),
+            Schedule::Ramp {
+                z: (z0, z1),
+                value: (v0, v1),
+            } => {
+                let (low, high) = (z0.min(*z1), z0.max(*z1));
+                if z < low - f32::EPSILON || z > high + f32::EPSILON {
+                    return None;
+                }
+                if (z1 - z0).abs() < f32::EPSILON {
+                    return Some(v1.round());
+                }
+                Some((v0 + (z - z0) / (z1 - z0) * (v1 - v0)).round())
+            }
+        }
+    }
+}
+
+// one row of the preview, what the layer starts with now and what the schedule sets
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LayerValue {
+    pub z: f32,
+    pub current: Option<f32>,
+    pub scheduled: Option<f32>,
+}
+
+impl LayerValue {
+    pub fn effective(&self) -> Option<f32> {
+        self.scheduled.or(self.current)
+    }
+}
+
+impl Parsed {
+    // the height of every layer and the move that goes up to it, in file order
+    // a layer starts at the first move to its height before its first extrusion, so z hops
+    // from the layers below don't count
+    pub fn layer_starts(&self) -> Vec<(f32, Id)> {
+        let mut seen = HashSet::new();
+        let mut out = Vec::new();
+        for id in &self.lines {
+            let Some(v) = self.vertices.get(id) else {
+                continue;
+            };
+            if v.label != Label::PlanarExtrustion || !seen.insert(v.to.z.to_bits()) {
+                continue;
+            }
+            let mut start = v;
+            while let Some(prev) = start.prev.and_then(|p| self.vertices.get(&p)) {
+                if prev.label == Label::Home || (prev.to.z - v.to.z).abs() > f32::EPSILON {
+                    break;
+                }
+                start = prev;
+            }
+            out.push((v.to.z, start.id));
+        }
+        out
+    }
+    // the value each layer starts with and what the schedule would change it to
+    pub fn schedule_preview(&self, param: Parameter, schedule: &Schedule) -> Vec<LayerValue> {
+        let starts = self
+            .layer_starts()
+            .into_iter()
+            .enumerate()
+            .map(|(i, (z, id))| (id, (i, z)))
+            .collect::<HashMap<Id, (usize, f32)>>();
+        let mut out = Vec::with_capacity(starts.len());
+        let mut current = None;
+        for id in &self.lines {
+            if let Some(value) = self.instructions.get(id).and_then(|ins| param.value(ins)) {
+                current = Some(value);
+            } else if let Some((i, z)) = starts.get(id) {
+                out.push(LayerValue {
+                    z: *z,
+                    current,
+                    scheduled: schedule.value(*i, *z),
+                });
+            }
+        }
+        out
+    }
+    // sets the parameter at the start of every scheduled layer it changes on, commands for it
+    // inside scheduled layers are removed, or for M109 set to the scheduled value so the wait
+    // stays, and the old value or the firmware default comes back after them
+    // returns how many commands were inserted
+    pub fn apply_schedule(
+        &mut self,
+        param: Parameter,
+        schedule: &Schedule,
+    ) -> Result<usize, Box<dyn Error>> {
+        let starts = self
+            .layer_starts()
+            .into_iter()
+            .enumerate()
+            .map(|(i, (z, id))| (id, schedule.value(i, z)))
+            .collect::<HashMap<Id, Option<f32>>>();
+        if starts.values().all(Option::is_none) {
+            return Err("the schedule covers no layers".into());
+        }
+        // the new lines with the commands to add, nothing changes until the whole file is done
+        let mut lines: Vec<Result<Id, Instruction>> = Vec::with_capacity(self.lines.len());
+        let mut removed = Vec::new();
+        let mut waits = Vec::new();
+        // the value the file had and whether it waited for it, and what the last layer was
+        // scheduled to
+        let mut original = None;
+        let mut wait = false;
+        let mut scheduled = None;
+        for line in &self.lines {
+            if let Some((value, ins)) = self
+                .instructions
+                .get(line)
+                .and_then(|ins| Some((param.value(ins)?, ins)))
+            {
+                original = Some(value);
+                wait = ins.first_word.0 == 'M' && ins.first_word.1.round() as i32 == 109;
+                if let Some(s) = scheduled {
+                    if wait {
+                        waits.push((*line, s));
+                    } else {
+                        removed.push(*line);
+                        continue;
+                    }
+                }
+            } else if let Some(value) = starts.get(line) {
+                let set = match (value, scheduled) {
+                    (Some(v), Some(s)) if *v == s => None,
+                    (Some(v), _) => Some((*v, false)),
+                    (None, Some(_)) => Some((
+                        original
+                            .or(param.default())
+                            .ok_or("the file never sets the temperature to go back to")?,
+                        wait,
+                    )),
+                    (None, None) => None,
+                };
+                if let Some((set, wait)) = set {
+                    lines.push(Err(param.instruction(set, wait)));
+                }
+                scheduled = *value;
+            }
+            lines.push(Ok(*line));
+        }
+        for line in removed {
+            self.remove_instruction(&line);
+        }
+        for (line, value) in waits {
+            let ins = self.instruction_mut(&line).unwrap();
+            for word in ins.params.iter_mut().flatten().filter(|w| w.0 == 'S') {
+                word.1 = value;
+            }
+        }
+        let mut inserted = 0;
+        let lines = lines
+            .into_iter()
+            .map(|line| {
+                line.unwrap_or_else(|ins| {
+                    inserted += 1;
+                    self.add_instruction(ins)
+                })
+            })
+            .collect();
+        *self.lines_mut() = lines;
+        self.assign_shapes();
+        Ok(inserted)
+    }
+}
+
+#[test]
+fn schedule_test() {
+    use super::emit::Emit;
+    let gcode = "G28\nM104 S200\nG1 X10 Y10 Z0.2 F1200\nG1 X20 E1\nG1 Z0.6\nG1 Z0.4\nG1 X10 E1\nM104 S205\nG1 Z0.6\nG1 X20 E1\nG1 Z0.8\nG1 X10 E1";
+    let mut gcode = super::read(gcode, true).expect("failed to parse");
+    // the hop to 0.6 in the second layer isn't where the third starts
+    let starts = gcode.layer_starts();
+    assert_eq!(
+        starts.iter().map(|s| s.0).collect::<Vec<f32>>(),
+        [0.2, 0.4, 0.6, 0.8]
+    );
+    assert_eq!(starts[2].1, gcode.lines[8]);
+    let steps = Schedule::parse_steps("1-2: 215, 3:210").expect("failed to parse steps");
+    assert_eq!(steps, Schedule::Steps(vec![(1, 2, 215.0), (3, 3, 210.0)]));
+    assert!(Schedule::parse_steps("2-1: 215").is_err());
+    let preview = gcode.schedule_preview(Parameter::Temperature, &steps);
+    assert_eq!(
+        preview.iter().map(|l| l.current).collect::<Vec<_>>(),
+        [Some(200.0), Some(200.0), Some(205.0), Some(205.0)]
+    );
+    assert_eq!(
+        preview.iter().map(|l| l.effective()).collect::<Vec<_>>(),
+        [Some(200.0), Some(215.0), Some(215.0), Some(210.0)]
+    );
+    assert_eq!(
+        gcode
+            .apply_schedule(Parameter::Temperature, &steps)
+            .unwrap(),
+        2
+    );
+    let out = gcode.emit(&gcode, false);
+    // the M104 S205 in the scheduled layers is gone
+    assert!(!out.contains("S205"));
+    assert_eq!(out.matches("M104").count(), 3);
+    assert!(out.find("M104 S200").unwrap() < out.find("Z0.2").unwrap());
+    let layer = |z: &str| out.find(&format!("G1 Z{} \n", z)).unwrap();
+    let (s215, s210) = (
+        out.find("M104 S215").unwrap(),
+        out.find("M104 S210").unwrap(),
+    );
+    assert!(s215 < layer("0.4") && s215 > out.find("Z0.2").unwrap());
+    assert!(s210 < layer("0.8") && s210 > s215);
+    // a fan ramp, the file never sets the fan so it goes back off after it
+    let ramp = Schedule::Ramp {
+        z: (0.2, 0.4),
+        value: (0.0, 255.0),
+    };
+    assert_eq!(ramp.value(0, 0.3), Some(128.0));
+    assert_eq!(gcode.apply_schedule(Parameter::Fan, &ramp).unwrap(), 3);
+    let preview = gcode.schedule_preview(Parameter::Fan, &ramp);
+    assert_eq!(
+        preview.iter().map(|l| l.current).collect::<Vec<_>>(),
+        [Some(0.0), Some(255.0), Some(0.0), Some(0.0)]
+    );
+    // waits keep waiting, for the scheduled value inside the schedule and the file's after
+    let text = "G28\nM109 S200\nG1 X10 Y10 Z0.2 F1200\nG1 X20 E1\nG1 Z0.4\nM109 S205\nG1 X10 E1\nG1 Z0.6\nG1 X20 E1";
+    let mut gcode = super::read(text, true).expect("failed to parse");
+    let steps = Schedule::Steps(vec![(1, 1, 215.0)]);
+    assert_eq!(
+        gcode
+            .apply_schedule(Parameter::Temperature, &steps)
+            .unwrap(),
+        2
+    );
+    let out = gcode.emit(&gcode, false);
+    let (s215, wait, s205) = (
+        out.find("M104 S215").unwrap(),
+        out.find("M109 S215").unwrap(),
+        out.find("M109 S205").unwrap(),
+    );
+    assert!(s215 < wait && wait < s205 && s205 < out.find("Z0.6").unwrap());
+    // nothing to go back to
+    let mut gcode = super::read(
+        "G28\nG1 X10 Y10 Z0.2 F1200\nG1 X20 E1\nG1 Z0.4\nG1 X10 E1",
+        true,
+    )
+    .expect("failed to parse");
+    assert!(gcode
+        .apply_schedule(
+            Parameter::Temperature,
+            &Schedule::Steps(vec![(0, 0, 215.0)])
+        )
+        .is_err());
+    assert_eq!(gcode.lines.len(), 5);
+    assert!(gcode
+        .apply_schedule(Parameter::Flow, &Schedule::Steps(vec![(9, 9, 90.0)]))
+        .is_err());
+}
@@ -0,0 +1,408 @@
+use super::{paths::ExtrusionPath, Id, Label, Parsed, Role};
+use std::collections::{HashMap, HashSet};
+
+// loops whose centers are closer than this on consecutive layers belong to the same object
+const OBJECT_TOLERANCE: f32 = 5.0;
+// seams closer than this to the previous layer's seam count as aligned
+const ALIGNED_TOLERANCE: f32 = 1.0;
+
+// where a closed loop starts and ends
+#[derive(Clone, Debug, PartialEq)]
+pub struct Seam {
+    pub shape: Id,
+    // the move that puts the nozzle at the seam before the loop is extruded
+    pub start: Id,
+    // the last extrusion of the loop
+    pub end: Id,
+    pub point: (f32, f32, f32),
+    pub role: Role,
+    // center of the loop, used to tell objects apart
+    pub center: (f32, f32),
+}
+
+// where batch alignment puts the start of every loop
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SeamAlignment {
+    // as close as possible to the line through two points
+    Line((f32, f32), (f32, f32)),
+    // largest y
+    Rear,
+    // where the loop turns the most
+    SharpestCorner,
+}
+
+// how well the seams of one object line up from layer to layer
+#[derive(Clone, Debug, PartialEq)]
+pub struct SeamStats {
+    pub center: (f32, f32),
+    pub layers: usize,
+    // rms distance of the seams from their mean position
+    pub spread: f32,
+    // mean distance between the seams of consecutive layers
+    pub mean_step: f32,
+    // share of layers whose seam is within tolerance of the one below
+    pub aligned: f32,
+}
+
+fn center(points: &[(f32, f32)]) -> (f32, f32) {
+    let n = points.len() as f32;
+    let (x, y) = points
+        .iter()
+        .fold((0.0, 0.0), |(x, y), p| (x + p.0, y + p.1));
+    (x / n, y / n)
+}
+
+fn dist(a: (f32, f32), b: (f32, f32)) -> f32 {
+    ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt()
+}
+
+fn dist_to_line(p: (f32, f32), a: (f32, f32), b: (f32, f32)) -> f32 {
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let len = (dx * dx + dy * dy).sqrt();
+    if len < f32::EPSILON {
+        return dist(p, a);
+    }
+    ((p.0 - a.0) * dy - (p.1 - a.1) * dx).abs() / len
+}
+
+// the point a distance d along a polyline, or its end
+fn point_along(points: &[(f32, f32)], mut d: f32) -> (f32, f32) {
+    for w in points.windows(2) {
+        let len = dist(w[0], w[1]);
+        if d <= len && len > 0.0 {
+            let t = d / len;
+            return (
+                w[0].0 + (w[1].0 - w[0].0) * t,
+                w[0].1 + (w[1].1 - w[0].1) * t,
+            );
+        }
+        d -= len;
+    }
+    *points.last().unwrap()
+}
+
+// index of the loop point where the alignment wants the seam
+// points[0] and points[n] are both the current seam so only 1..n are candidates
+fn choose_start(points: &[(f32, f32)], alignment: SeamAlignment) -> usize {
+    let n = points.len() - 1;
+    let score = |i: usize| -> f32 {
+        let p = points[i];
+        match alignment {
+            SeamAlignment::Line(a, b) => dist_to_line(p, a, b),
+            SeamAlignment::Rear => -p.1,
+            SeamAlignment::SharpestCorner => {
+                let (a, b) = (points[i - 1], points[i % n + 1]);
+                let turn = (p.1 - a.1).atan2(p.0 - a.0) - (b.1 - p.1).atan2(b.0 - p.0);
+                -turn.sin().atan2(turn.cos()).abs()
+            }
+        }
+    };
+    // the current seam wins ties so aligned loops are left alone
+    (1..=n).fold(n, |best, i| {
+        if score(i) < score(best) - 1e-4 {
+            i
+        } else {
+            best
+        }
+    })
+}
+
+fn same_xy(a: (f32, f32), b: (f32, f32)) -> bool {
+    dist(a, b) < 1e-4
+}
+
+impl Parsed {
+    fn line_positions(&self) -> HashMap<Id, usize> {
+        self.lines
+            .iter()
+            .enumerate()
+            .map(|(i, id)| (*id, i))
+            .collect()
+    }
+    fn xy(&self, id: &Id) -> (f32, f32) {
+        let v = self.vertices.get(id).unwrap();
+        (v.to.x, v.to.y)
+    }
+    fn set_xy(&mut self, id: &Id, (x, y): (f32, f32)) {
+        let v = self.vertex_mut(id).unwrap();
+        v.to.x = x;
+        v.to.y = y;
+    }
+    // rotates a closed loop so it starts where its kth extrusion ends
+    // the moves parked at the old seam follow it and wipes are laid back along the loop
+    // links are left stale for set_counts, returns the vertices whose moves changed
+    fn rotate_loop(
+        &mut self,
+        path: &ExtrusionPath,
+        k: usize,
+        positions: &mut HashMap<Id, usize>,
+    ) -> Vec<Id> {
+        let points = path.points(self);
+        let n = path.vertices.len();
+        let (seam, old_start, old_end) = (points[k], points[0], points[n]);
+        let (first, last) = (path.vertices[0], path.vertices[n - 1]);
+        let mut touched = vec![first, path.vertices[k]];
+
+        // the first extrusion now starts where the loop used to end
+        let old_len = self
+            .vertices
+            .get(&path.start)
+            .unwrap()
+            .to
+            .dist(&self.vertices.get(&first).unwrap().to);
+        let new_len = self
+            .vertices
+            .get(&last)
+            .unwrap()
+            .to
+            .dist(&self.vertices.get(&first).unwrap().to);
+        if old_len > f32::EPSILON {
+            self.vertex_mut(&first).unwrap().to.e *= new_len / old_len;
+        }
+
+        let (i0, i1) = (positions[&first], positions[&last]);
+        let split = positions[&path.vertices[k - 1]] + 1;
+        self.lines_mut()[i0..=i1].rotate_left(split - i0);
+        for (i, id) in self.lines[i0..=i1].iter().enumerate() {
+            positions.insert(*id, i0 + i);
+        }
+
+        // the travel in and any deretraction or z move at the old seam
+        for i in (0..i0).rev() {
+            let id = self.lines[i];
+            let Some(v) = self.vertices.get(&id) else {
+                continue;
+            };
+            if v.extrusion_move() || v.label == Label::Home || !same_xy(self.xy(&id), old_start) {
+                break;
+            }
+            self.set_xy(&id, seam);
+            touched.push(id);
+        }
+
+        // the retraction, wipe and z moves leaving the old seam
+        let mut loop_points = vec![seam];
+        loop_points.extend(&points[k + 1..]);
+        loop_points.extend(&points[1..=k]);
+        let (mut wiped, mut from, mut placed) = (0.0, old_end, seam);
+        for i in i1 + 1..self.lines.len() {
+            let id = self.lines[i];
+            let Some(v) = self.vertices.get(&id) else {
+                continue;
+            };
+            let to = self.xy(&id);
+            if v.extrusion_move() {
+                break;
+            } else if same_xy(to, from) {
+                self.set_xy(&id, placed);
+            } else if v.label == Label::Wipe {
+                wiped += dist(from, to);
+                from = to;
+                placed = point_along(&loop_points, wiped);
+                self.set_xy(&id, placed);
+            } else {
+                touched.push(id);
+                break;
+            }
+            touched.push(id);
+        }
+        touched
+    }
+    // rotates every closed loop for which choose returns a new start index
+    fn relocate_seams(
+        &mut self,
+        choose: impl Fn(&ExtrusionPath, &[(f32, f32)]) -> Option<usize>,
+    ) -> usize {
+        let mut positions = self.line_positions();
+        let mut touched = Vec::new();
+        let mut moved = 0;
+        for path in self.extrusion_paths() {
+            if !path.is_closed(self) {
+                continue;
+            }
+            let points = path.points(self);
+            match choose(&path, &points) {
+                Some(k) if k > 0 && k < path.vertices.len() => {
+                    touched.extend(self.rotate_loop(&path, k, &mut positions));
+                    moved += 1;
+                }
+                _ => (),
+            }
+        }
+        if moved > 0 {
+            self.set_counts();
+            for id in touched {
+                self.relabel(&id);
+            }
+            self.assign_shapes();
+        }
+        moved
+    }
+    // starts every loop holding one of the vertices where that vertex ends
+    pub fn set_seams(&mut self, vertices: &HashSet<Id>) -> usize {
+        self.relocate_seams(|path, _| {
+            path.vertices
+                .iter()
+                .position(|v| vertices.contains(v))
+                .map(|i| i + 1)
+        })
+    }
+    // starts the loop holding the vertex at its point nearest to the target
+    pub fn set_seam_near(&mut self, vertex: &Id, target: (f32, f32)) -> bool {
+        self.relocate_seams(|path, points| {
+            if path.start != *vertex && !path.vertices.contains(vertex) {
+                return None;
+            }
+            Some(choose_start(points, SeamAlignment::Line(target, target)))
+        }) > 0
+    }
+    // moves the seam of every loop on every layer
+    pub fn align_seams(&mut self, alignment: SeamAlignment) -> usize {
+        self.relocate_seams(|_, points| Some(choose_start(points, alignment)))
+    }
+    fn seam(&self, path: &ExtrusionPath) -> Seam {
+        let start = self.vertices.get(&path.start).unwrap();
+        let role = self.vertices.get(&path.vertices[0]).unwrap().role;
+        Seam {
+            shape: path.shape,
+            start: path.start,
+            end: *path.vertices.last().unwrap(),
+            point: (start.to.x, start.to.y, path.layer),
+            role,
+            center: center(&path.points(self)),
+        }
+    }
+    // seams of every closed loop in print order
+    pub fn seams(&self) -> Vec<Seam> {
+        self.extrusion_paths()
+            .iter()
+            .filter(|p| p.is_closed(self))
+            .map(|p| self.seam(p))
+            .collect()
+    }
+    // the vertices sitting at a seam, both where the loop starts and where it closes
+    pub fn seam_vertices(&self) -> Vec<Id> {
+        self.seams().iter().flat_map(|s| [s.start, s.end]).collect()
+    }
+    // seams of outer perimeters grouped into objects by stacking loops across layers
+    // if nothing was recognized as an outer perimeter every loop is used
+    pub fn seam_stats(&self) -> Vec<SeamStats> {
+        let seams = self.seams();
+        let outer = seams.iter().any(|s| s.role == Role::OuterPerimeter);
+        let mut objects: Vec<Vec<&Seam>> = Vec::new();
+        for seam in seams
+            .iter()
+            .filter(|s| !outer || s.role == Role::OuterPerimeter)
+        {
+            let object = objects
+                .iter_mut()
+                .find(|o| dist(o.last().unwrap().center, seam.center) < OBJECT_TOLERANCE);
+            match object {
+                // one seam per object per layer, the first loop printed wins
+                Some(o) if o.last().unwrap().point.2 == seam.point.2 => (),
+                Some(o) => o.push(seam),
+                None => objects.push(vec![seam]),
+            }
+        }
+        objects
+            .iter()
+            .map(|o| {
+                let points = o.iter().map(|s| (s.point.0, s.point.1)).collect::<Vec<_>>();
+                let mean = center(&points);
+                let spread = (points.iter().map(|p| dist(*p, mean).powi(2)).sum::<f32>()
+                    / points.len() as f32)
+                    .sqrt();
+                let steps = points
+                    .windows(2)
+                    .map(|w| dist(w[0], w[1]))
+                    .collect::<Vec<f32>>();
+                let (mean_step, aligned) = if steps.is_empty() {
+                    (0.0, 1.0)
+                } else {
+                    let n = steps.len() as f32;
+                    (
+                        steps.iter().sum::<f32>() / n,
+                        steps.iter().filter(|s| **s < ALIGNED_TOLERANCE).count() as f32 / n,
+                    )
+                };
+                SeamStats {
+                    center: center(&o.iter().map(|s| s.center).collect::<Vec<_>>()),
+                    layers: o.len(),
+                    spread,
+                    mean_step,
+                    aligned,
+                }
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn seams_test() {
+    // two objects, the left one with its seam in the same corner every layer
+    let mut gcode = String::from("G28\n");
+    for (i, z) in ["0.2", "0.4", "0.6"].iter().enumerate() {
+        gcode += &format!(
+            "G1 X10 Y10 Z{}\nG1 X20 E1\nG1 Y20 E1\nG1 X10 E1\nG1 Y10 E1\n",
+            z
+        );
+        let corners = [(60, 10), (60, 20), (50, 20), (50, 10)];
+        let (x, y) = corners[(i + 3) % 4];
+        gcode += &format!("G1 X{} Y{}\n", x, y);
+        for j in 0..4 {
+            let (x, y) = corners[(i + j) % 4];
+            gcode += &format!("G1 X{} Y{} E1\n", x, y);
+        }
+    }
+    gcode += "G1 Z1";
+    let gcode = super::read(&gcode, true).expect("failed to parse");
+    let seams = gcode.seams();
+    assert_eq!(seams.len(), 6);
+    assert_eq!(seams[0].point, (10.0, 10.0, 0.2));
+    assert_eq!(seams[0].start, gcode.lines[1]);
+    assert_eq!(seams[0].end, gcode.lines[5]);
+    assert_eq!(gcode.seam_vertices().len(), 12);
+    let stats = gcode.seam_stats();
+    assert_eq!(stats.len(), 2);
+    assert_eq!(stats[0].layers, 3);
+    assert_eq!(stats[0].spread, 0.0);
+    assert_eq!(stats[0].aligned, 1.0);
+    assert_eq!(stats[1].mean_step, 10.0);
+    assert_eq!(stats[1].aligned, 0.0);
+}
+
+#[test]
+fn relocate_test() {
+    let gcode = "G28\nG1 X10 Y10 Z0.2 F1200\nG1 E1\nG1 X20 E1\nG1 Y20 E1\nG1 X10 E1 F600\nG1 Y10 E1\nG1 E-1\nG1 X50";
+    let mut gcode = super::read(gcode, true).expect("failed to parse");
+    let original = gcode.lines.clone();
+    assert!(gcode.set_seams(&HashSet::from([original[4]])) == 1);
+    let order = [0, 1, 2, 5, 6, 3, 4, 7, 8].map(|i| original[i]);
+    assert_eq!(gcode.lines, order);
+    for i in [1, 2, 7] {
+        assert_eq!(gcode.xy(&original[i]), (20.0, 20.0));
+    }
+    let v = gcode.vertices.get(&original[5]).unwrap();
+    assert_eq!(v.prev, Some(original[2]));
+    assert_eq!(v.count, 3);
+    assert_eq!(
+        gcode.vertices.get(&original[2]).unwrap().next,
+        Some(original[5])
+    );
+    assert_eq!(
+        gcode.vertices.get(&original[4]).unwrap().next,
+        Some(original[7])
+    );
+    let e = gcode.vertices.values().map(|v| v.to.e).sum::<f32>();
+    assert_eq!(e, 4.0);
+    assert_eq!(gcode.seams()[0].point, (20.0, 20.0, 0.2));
+    // the seam is already as far back as the loop goes
+    assert_eq!(gcode.align_seams(SeamAlignment::Rear), 0);
+    assert!(gcode.set_seam_near(&original[3], (9.0, 9.0)));
+    assert_eq!(gcode.seams()[0].point, (10.0, 10.0, 0.2));
+    assert_eq!(
+        gcode.align_seams(SeamAlignment::Line((20.0, 0.0), (20.0, 5.0))),
+        1
+    );
+    assert_eq!(gcode.seams()[0].point.0, 20.0);
+}
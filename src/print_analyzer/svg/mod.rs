@@ -0,0 +1,227 @@
+use super::{flow::Bead, paths::CLOSED_TOLERANCE, Id, Label, Parsed, Role, Shape};
+use std::collections::HashMap;
+
+// consecutive moves with the same width this close are drawn as one polyline
+const WIDTH_TOLERANCE: f32 = 0.01;
+// space around the drawing in mm
+const MARGIN: f32 = 2.0;
+
+// a run of moves of one kind within a shape on a single layer
+#[derive(Clone, Debug, PartialEq)]
+pub struct Polyline {
+    pub shape: Id,
+    pub label: Label,
+    pub role: Role,
+    pub points: Vec<(f32, f32)>,
+    pub width: f32,
+}
+
+impl Polyline {
+    pub fn is_closed(&self) -> bool {
+        let (a, b) = (self.points[0], *self.points.last().unwrap());
+        self.points.len() > 3 && (a.0 - b.0).hypot(a.1 - b.1) < CLOSED_TOLERANCE
+    }
+    fn color(&self) -> &'static str {
+        match (self.label, self.role) {
+            (Label::PlanarExtrustion | Label::NonPlanarExtrusion, role) => match role {
+                Role::OuterPerimeter => "#d62728",
+                Role::InnerPerimeter => "#ff7f0e",
+                Role::Infill => "#2ca02c",
+                Role::SkirtBrim => "#9467bd",
+                Role::Purge => "#8c564b",
+                Role::Unknown => "#7f7f7f",
+            },
+            (Label::Wipe, _) => "#17becf",
+            _ => "#1f77b4",
+        }
+    }
+}
+
+// the layer a move ending at z is drawn on, moves between layers go on the one above like
+// classify_support puts them, so deformed moves aren't dropped
+fn layer_of(layers: &[f32], z: f32) -> usize {
+    layers
+        .partition_point(|l| *l < z - f32::EPSILON)
+        .min(layers.len().saturating_sub(1))
+}
+
+impl Parsed {
+    // polylines of the moves on layer z, extrusions only unless travel is set
+    pub fn layer_polylines(&self, z: f32, travel: bool) -> Vec<Polyline> {
+        let layers = self.layers();
+        let layer = layer_of(&layers, z);
+        self.polylines(&layers, layer, travel, self.shapes.iter(), &self.beads())
+    }
+    // the polylines of the layer found in the given shapes
+    fn polylines<'a>(
+        &self,
+        layers: &[f32],
+        layer: usize,
+        travel: bool,
+        shapes: impl Iterator<Item = &'a Shape>,
+        beads: &HashMap<Id, Bead>,
+    ) -> Vec<Polyline> {
+        let mut out = Vec::new();
+        for shape in shapes {
+            let mut current: Option<Polyline> = None;
+            for line in &shape.lines {
+                let Some(v) = self.vertices.get(line) else {
+                    continue;
+                };
+                let drawn = layer_of(layers, v.to.z) == layer
+                    && (v.extrusion_move()
+                        || travel && matches!(v.label, Label::TravelMove | Label::Wipe));
+                let Some(prev) = v.prev.filter(|_| drawn) else {
+                    out.extend(current.take());
+                    continue;
+                };
+                let width = if v.extrusion_move() {
+                    beads.get(line).map_or(0.4, |b| b.width)
+                } else {
+                    0.1
+                };
+                let to = (v.to.x, v.to.y);
+                if let Some(c) = current.as_mut() {
+                    let from = *c.points.last().unwrap();
+                    let p = self.vertices.get(&prev).unwrap();
+                    if c.label == v.label
+                        && c.role == v.role
+                        && (c.width - width).abs() < WIDTH_TOLERANCE
+                        && from == (p.to.x, p.to.y)
+                    {
+                        c.points.push(to);
+                        continue;
+                    }
+                    out.extend(current.take());
+                }
+                let p = self.vertices.get(&prev).unwrap();
+                current = Some(Polyline {
+                    shape: shape.id,
+                    label: v.label,
+                    role: v.role,
+                    points: vec![(p.to.x, p.to.y), to],
+                    width,
+                });
+            }
+            out.extend(current);
+        }
+        out
+    }
+    // the layer as an svg drawing in mm, one group per shape
+    pub fn layer_svg(&self, z: f32, travel: bool) -> String {
+        svg(z, &self.layer_polylines(z, travel))
+    }
+    // writes one svg per layer into dir, returns how many were written
+    pub fn write_layer_svgs(&self, dir: &str, travel: bool) -> Result<usize, std::io::Error> {
+        std::fs::create_dir_all(dir)?;
+        let layers = self.layers();
+        if layers.is_empty() {
+            return Ok(0);
+        }
+        let beads = self.beads();
+        // the shapes with moves on each layer, so every layer only looks at its own
+        let mut on_layer: Vec<Vec<&Shape>> = vec![Vec::new(); layers.len()];
+        for shape in &self.shapes {
+            for line in &shape.lines {
+                let Some(v) = self.vertices.get(line) else {
+                    continue;
+                };
+                let i = layer_of(&layers, v.to.z);
+                if !on_layer[i].last().is_some_and(|s| s.id == shape.id) {
+                    on_layer[i].push(shape);
+                }
+            }
+        }
+        for (i, (z, shapes)) in layers.iter().zip(on_layer).enumerate() {
+            let polylines = self.polylines(&layers, i, travel, shapes.into_iter(), &beads);
+            let path = std::path::Path::new(dir).join(format!("layer_{:04}_z{:.2}.svg", i, z));
+            std::fs::write(path, svg(*z, &polylines))?;
+        }
+        Ok(layers.len())
+    }
+}
+
+// the polylines as an svg drawing in mm, one group per shape
+fn svg(z: f32, polylines: &[Polyline]) -> String {
+    let (mut x0, mut y0, mut x1, mut y1) = (f32::MAX, f32::MAX, f32::MIN, f32::MIN);
+    for (x, y) in polylines.iter().flat_map(|p| p.points.iter()) {
+        (x0, y0, x1, y1) = (x0.min(*x), y0.min(*y), x1.max(*x), y1.max(*y));
+    }
+    if polylines.is_empty() {
+        (x0, y0, x1, y1) = (0.0, 0.0, 0.0, 0.0);
+    }
+    let (x0, y0) = (x0 - MARGIN, y0 - MARGIN);
+    let (w, h) = (x1 - x0 + MARGIN, y1 - y0 + MARGIN);
+    let mut out = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}mm\" height=\"{h}mm\" viewBox=\"0 0 {w} {h}\">\n"
+    );
+    out += &format!("<title>z {}</title>\n", z);
+    let mut shape = None;
+    for p in polylines {
+        if shape != Some(p.shape) {
+            if shape.is_some() {
+                out += "</g>\n";
+            }
+            out += &format!("<g id=\"shape-{}\">\n", p.shape.0);
+            shape = Some(p.shape);
+        }
+        // svg y grows downwards
+        let points = p
+            .points
+            .iter()
+            .map(|(x, y)| format!("{},{}", x - x0, h - (y - y0)))
+            .collect::<Vec<String>>();
+        let (tag, points) = if p.is_closed() {
+            ("polygon", &points[..points.len() - 1])
+        } else {
+            ("polyline", &points[..])
+        };
+        out += &format!(
+            "<{} points=\"{}\" fill=\"none\" stroke=\"{}\" stroke-width=\"{}\" stroke-linejoin=\"round\" stroke-linecap=\"round\"/>\n",
+            tag,
+            points.join(" "),
+            p.color(),
+            p.width
+        );
+    }
+    if shape.is_some() {
+        out += "</g>\n";
+    }
+    out + "</svg>\n"
+}
+
+#[test]
+fn svg_test() {
+    let gcode = "G28\nG1 X10 Y10 Z0.2 F1200\nG1 X20 E1\nG1 Y20 E1\nG1 X10 E1\nG1 Y10 E1\nG1 X30\nG1 X40 E1\nG1 X50 E2\nG1 Z0.4\nG1 X60 E1";
+    let gcode = super::read(gcode, true).expect("failed to parse");
+    let polylines = gcode.layer_polylines(0.2, false);
+    assert_eq!(polylines.len(), 3);
+    assert!(polylines[0].is_closed());
+    assert_eq!(polylines[0].points.len(), 5);
+    // doubling the flow over the same length makes the second line wider
+    assert!(polylines[2].width > polylines[1].width);
+    assert_eq!(gcode.layer_polylines(0.2, true).len(), 4);
+    let svg = gcode.layer_svg(0.2, false);
+    assert_eq!(svg.matches("<polygon").count(), 1);
+    assert_eq!(svg.matches("<polyline").count(), 2);
+    assert!(svg.contains("<polygon points=\"2,12 12,12 12,2 2,2\""));
+    assert_eq!(gcode.layer_polylines(0.4, false).len(), 1);
+    // the files get the same drawings as the layers on their own
+    let dir = std::env::temp_dir().join("svg_test");
+    let written = gcode
+        .write_layer_svgs(dir.to_str().unwrap(), false)
+        .expect("failed to write");
+    assert_eq!(written, 2);
+    for (i, z) in gcode.layers().iter().enumerate() {
+        let path = dir.join(format!("layer_{:04}_z{:.2}.svg", i, z));
+        let file = std::fs::read_to_string(path).expect("failed to read");
+        assert_eq!(file, gcode.layer_svg(*z, false));
+    }
+    // a loop that ends a little off its start is still closed, and a deformed move rising
+    // towards the next layer is drawn on that layer
+    let gcode = "G28\nG1 X10 Y10 Z0.2 F1200\nG1 X20 E1\nG1 Y20 E1\nG1 X10 E1\nG1 X10.1 Y10 E1\nG1 X20 Z0.3 E1\nG1 Z0.4\nG1 X30 E1";
+    let gcode = super::read(gcode, true).expect("failed to parse");
+    assert!(gcode.layer_polylines(0.2, false)[0].is_closed());
+    let upper = gcode.layer_polylines(0.4, false);
+    assert_eq!(upper.iter().map(|p| p.points.len() - 1).sum::<usize>(), 2);
+}
@@ -1,47 +1,359 @@
-use super::{Id, Parsed};
-use bevy::math::Vec3;
+use super::{
+    bounds::{BoundsKind, BuildVolume, OutOfBounds},
+    edit::Feature,
+    Id, Label, Parsed,
+};
+use bevy::math::{Mat4, Vec3};
 use core::f32::consts::PI;
+use std::collections::HashSet;
+
+// a transform made from per axis values, all but the matrix are taken about an origin
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Affine {
+    Mirror { x: bool, y: bool },
+    Scale(Vec3),
+    // x moves by xy per mm of y and xz per mm of z, y by yz per mm of z
+    Shear { xy: f32, xz: f32, yz: f32 },
+    // used as is
+    Matrix(Mat4),
+}
+
+impl Affine {
+    pub fn matrix(&self, origin: Vec3) -> Mat4 {
+        let about = |m: Mat4| Mat4::from_translation(origin) * m * Mat4::from_translation(-origin);
+        match self {
+            Affine::Mirror { x, y } => about(Mat4::from_scale(Vec3::new(
+                if *x { -1.0 } else { 1.0 },
+                if *y { -1.0 } else { 1.0 },
+                1.0,
+            ))),
+            Affine::Scale(scale) => about(Mat4::from_scale(*scale)),
+            Affine::Shear { xy, xz, yz } => about(Mat4::from_cols_array_2d(&[
+                [1.0, 0.0, 0.0, 0.0],
+                [*xy, 1.0, 0.0, 0.0],
+                [*xz, *yz, 1.0, 0.0],
+                [0.0, 0.0, 0.0, 1.0],
+            ])),
+            Affine::Matrix(m) => *m,
+        }
+    }
+}
+
+// rotates p about origin by euler angles in degrees, x first, then y, then z
+fn rotate_point(p: Vec3, origin: Vec3, angle_x: f32, angle_y: f32, angle_z: f32) -> Vec3 {
+    // Translate point back to origin
+    let mut x = p.x - origin.x;
+    let mut y = p.y - origin.y;
+    let mut z = p.z - origin.z;
+
+    // Convert angles from degrees to radians
+    let angle_x = angle_x * PI / 180.0;
+    let angle_y = angle_y * PI / 180.0;
+    let angle_z = angle_z * PI / 180.0;
+
+    // Rotation around X-axis
+    let new_y = y * angle_x.cos() - z * angle_x.sin();
+    let new_z = y * angle_x.sin() + z * angle_x.cos();
+    y = new_y;
+    z = new_z;
+
+    // Rotation around Y-axis
+    let new_x = x * angle_y.cos() + z * angle_y.sin();
+    let new_z = -x * angle_y.sin() + z * angle_y.cos();
+    x = new_x;
+    z = new_z;
+
+    // Rotation around Z-axis
+    let new_x = x * angle_z.cos() - y * angle_z.sin();
+    let new_y = x * angle_z.sin() + y * angle_z.cos();
+    x = new_x;
+    y = new_y;
+
+    // Translate point back
+    Vec3::new(x + origin.x, y + origin.y, z + origin.z)
+}
 
 impl Parsed {
-    pub fn rotate(&mut self, vertex: &Id, origin: Vec3, angle_x: f32, angle_y: f32, angle_z: f32) {
-        let v = self.vertices.get_mut(vertex).unwrap();
-        // Translate point back to origin
-        let mut x = v.to.x - origin.x;
-        let mut y = v.to.y - origin.y;
-        let mut z = v.to.z - origin.z;
-
-        // Convert angles from degrees to radians
-        let angle_x = angle_x * PI / 180.0;
-        let angle_y = angle_y * PI / 180.0;
-        let angle_z = angle_z * PI / 180.0;
-
-        // Rotation around X-axis
-        let new_y = y * angle_x.cos() - z * angle_x.sin();
-        let new_z = y * angle_x.sin() + z * angle_x.cos();
-        y = new_y;
-        z = new_z;
-
-        // Rotation around Y-axis
-        let new_x = x * angle_y.cos() + z * angle_y.sin();
-        let new_z = -x * angle_y.sin() + z * angle_y.cos();
-        x = new_x;
-        z = new_z;
-
-        // Rotation around Z-axis
-        let new_x = x * angle_z.cos() - y * angle_z.sin();
-        let new_y = x * angle_z.sin() + y * angle_z.cos();
-        x = new_x;
-        y = new_y;
-
-        // Translate point back
-        v.to.x = x + origin.x;
-        v.to.y = y + origin.y;
-        v.to.z = z + origin.z;
-    }
-    pub fn scale(&mut self, vertex: &Id, origin: Vec3, scale: f32) {
-        let v = self.vertices.get_mut(vertex).unwrap();
-        v.to.x = origin.x + (v.to.x - origin.x) * scale;
-        v.to.y = origin.y + (v.to.y - origin.y) * scale;
-        v.to.z = origin.z + (v.to.z - origin.z) * scale;
+    // moves the end point of every vertex through f, returns how many moved
+    // every segment that changes length keeps its extrusion per mm, including the ones
+    // joining the vertices to the rest of the path
+    pub fn transform(&mut self, vertices: &HashSet<Id>, f: impl Fn(Vec3) -> Vec3) -> usize {
+        self.modify(&Feature::Vertices(vertices.clone()), |pos| {
+            let p = f(Vec3::new(pos.x, pos.y, pos.z));
+            (pos.x, pos.y, pos.z) = (p.x, p.y, p.z);
+        })
+    }
+    // applies the matrix to the vertices, a mirrored loop is printed backwards from its seam
+    // so it keeps the direction it was sliced with
+    pub fn affine(&mut self, vertices: &HashSet<Id>, matrix: Mat4) -> usize {
+        let count = self.transform(vertices, |p| matrix.transform_point3(p));
+        if matrix.determinant() < 0.0 {
+            for path in self.extrusion_paths() {
+                if path.is_closed(self)
+                    && vertices.contains(&path.start)
+                    && path.vertices.iter().all(|id| vertices.contains(id))
+                {
+                    self.reverse_loop(&path.start, &path.vertices);
+                }
+            }
+        }
+        count
+    }
+    // runs a closed loop the other way round, starting and ending where it did
+    fn reverse_loop(&mut self, start: &Id, vertices: &[Id]) {
+        let mut points = std::iter::once(start)
+            .chain(vertices)
+            .map(|id| self.vertices.get(id).unwrap().to)
+            .collect::<Vec<_>>();
+        let mut moves = vertices
+            .iter()
+            .map(|id| *self.vertices.get(id).unwrap())
+            .collect::<Vec<_>>();
+        points.pop();
+        moves.reverse();
+        for ((id, p), v) in vertices.iter().zip(points.into_iter().rev()).zip(moves) {
+            let vertex = self.vertex_mut(id).unwrap();
+            vertex.to = v.to;
+            (vertex.to.x, vertex.to.y, vertex.to.z) = (p.x, p.y, p.z);
+            vertex.support = v.support;
+            vertex.role = v.role;
+        }
+    }
+    // first and last line of the print itself, from the move to the start of the first extrusion
+    // that isn't purging to the last extrusion, with the moves that stay at either end's xy
+    // (lowering, lifting, retracting) and any wipe after it
+    // purging is found from the moves, so it doesn't rely on the roles set when the file was read
+    pub fn print_range(&self) -> Option<(usize, usize)> {
+        let purge = self.purge_moves();
+        let print = |id: &Id| {
+            !purge.contains(id) && self.vertices.get(id).is_some_and(|v| v.extrusion_move())
+        };
+        let first = self.lines.iter().position(print)?;
+        let last = self.lines.iter().rposition(print)?;
+        let xy = |i: usize| self.vertices.get(&self.lines[i]).map(|v| (v.to.x, v.to.y));
+        let mut start = first;
+        let mut at = None;
+        for i in (0..first).rev() {
+            let Some(v) = self.vertices.get(&self.lines[i]) else {
+                continue;
+            };
+            if v.label == Label::Home || at.is_some_and(|at| at != (v.to.x, v.to.y)) {
+                break;
+            }
+            at = Some((v.to.x, v.to.y));
+            start = i;
+        }
+        let mut end = last;
+        let mut at = xy(last);
+        for i in last + 1..self.lines.len() {
+            let Some(v) = self.vertices.get(&self.lines[i]) else {
+                continue;
+            };
+            if v.label != Label::Wipe && at != Some((v.to.x, v.to.y)) {
+                break;
+            }
+            at = Some((v.to.x, v.to.y));
+            end = i;
+        }
+        Some((start, end))
+    }
+    // moves the print by (dx, dy) and turns it about its center, leaving start and end gcode
+    // and purge lines in place, returns how many moves changed
+    // nothing is changed if any of the moves would end up off the bed
+    pub fn move_print(
+        &mut self,
+        dx: f32,
+        dy: f32,
+        angle_z: f32,
+        volume: &BuildVolume,
+    ) -> Result<usize, Vec<OutOfBounds>> {
+        let Some((start, end)) = self.print_range() else {
+            return Ok(0);
+        };
+        let lines = &self.lines[start..=end];
+        // the center of the extrusions' bounding box
+        let (x0, y0, x1, y1) = lines
+            .iter()
+            .filter_map(|id| self.vertices.get(id))
+            .filter(|v| v.extrusion_move())
+            .fold(
+                (
+                    f32::INFINITY,
+                    f32::INFINITY,
+                    f32::NEG_INFINITY,
+                    f32::NEG_INFINITY,
+                ),
+                |(x0, y0, x1, y1), v| {
+                    (
+                        x0.min(v.to.x),
+                        y0.min(v.to.y),
+                        x1.max(v.to.x),
+                        y1.max(v.to.y),
+                    )
+                },
+            );
+        let (cx, cy) = ((x0 + x1) / 2.0, (y0 + y1) / 2.0);
+        let (sin, cos) = (angle_z * PI / 180.0).sin_cos();
+        let mut moved = Vec::with_capacity(lines.len());
+        let mut out = Vec::new();
+        for (i, id) in lines.iter().enumerate() {
+            let Some(v) = self.vertices.get(id) else {
+                continue;
+            };
+            let (x, y) = (v.to.x - cx, v.to.y - cy);
+            let (x, y) = (cx + x * cos - y * sin + dx, cy + x * sin + y * cos + dy);
+            if !volume.contains_xy(x, y) {
+                out.push(OutOfBounds {
+                    id: *id,
+                    line: start + i + 1,
+                    kind: BoundsKind::OffBed,
+                });
+            }
+            moved.push((*id, x, y));
+        }
+        if !out.is_empty() {
+            return Err(out);
+        }
+        // a rigid move keeps every length, so flows and labels stay as they are
+        for (id, x, y) in &moved {
+            let v = self.vertex_mut(id).unwrap();
+            (v.to.x, v.to.y) = (*x, *y);
+        }
+        Ok(moved.len())
+    }
+    pub fn rotate(
+        &mut self,
+        vertices: &HashSet<Id>,
+        origin: Vec3,
+        angle_x: f32,
+        angle_y: f32,
+        angle_z: f32,
+    ) -> usize {
+        self.transform(vertices, |p| {
+            rotate_point(p, origin, angle_x, angle_y, angle_z)
+        })
+    }
+    pub fn scale(&mut self, vertices: &HashSet<Id>, origin: Vec3, scale: f32) -> usize {
+        self.transform(vertices, |p| origin + (p - origin) * scale)
+    }
+}
+
+#[test]
+fn transform_test() {
+    let gcode =
+        "G28\nG1 X10 Y10 Z0.2 F1200\nG1 X20 E1\nG1 Y20 E1\nG1 X10 E1\nG1 Y10 E1\nG1 E-1\nG1 X30";
+    let mut gcode = super::read(gcode, true).expect("failed to parse");
+    let ids = gcode.lines.clone();
+    let e = |gcode: &Parsed, i: usize| gcode.vertices.get(&ids[i]).unwrap().to.e;
+    // scaling the whole square twice as large doubles its flow, the retraction is left alone
+    let square = ids[1..7].iter().copied().collect::<HashSet<Id>>();
+    assert_eq!(gcode.scale(&square, Vec3::new(15.0, 15.0, 0.2), 2.0), 6);
+    for i in 2..6 {
+        assert!((e(&gcode, i) - 2.0).abs() < 1e-5);
+    }
+    assert_eq!(e(&gcode, 6), -1.0);
+    let v = gcode.vertices.get(&ids[1]).unwrap().to;
+    assert_eq!((v.x, v.y, v.z), (5.0, 5.0, 0.2));
+    // rotating one corner changes the segments on both sides of it
+    let corner = HashSet::from([ids[3]]);
+    gcode.rotate(&corner, Vec3::new(25.0, 15.0, 0.2), 0.0, 0.0, 90.0);
+    let v = gcode.vertices.get(&ids[3]).unwrap().to;
+    assert!((v.x - 15.0).abs() < 1e-4 && (v.y - 15.0).abs() < 1e-4);
+    let per_mm = |gcode: &Parsed, i: usize| e(gcode, i) / gcode.dist_from_prev(&ids[i]);
+    for i in 2..6 {
+        assert!((per_mm(&gcode, i) - 0.1).abs() < 1e-5);
+    }
+}
+
+#[test]
+fn affine_test() {
+    let gcode =
+        "G28\nG1 X10 Y10 Z0.2 F1200\nG1 X20 E1 F600\nG1 Y15 E0.5\nG1 X10 E1\nG1 Y10 E0.5\nG1 E-1";
+    let mut gcode = super::read(gcode, true).expect("failed to parse");
+    let ids = gcode.lines.clone();
+    let all = ids.iter().copied().collect::<HashSet<Id>>();
+    let counter_clockwise = |gcode: &Parsed| {
+        let p = gcode.extrusion_paths()[0].points(gcode);
+        p.windows(2)
+            .map(|w| w[0].0 * w[1].1 - w[1].0 * w[0].1)
+            .sum::<f32>()
+            > 0.0
+    };
+    assert!(counter_clockwise(&gcode));
+    let origin = Vec3::new(15.0, 0.0, 0.0);
+    let mirror = Affine::Mirror { x: true, y: false }.matrix(origin);
+    gcode.affine(&all, mirror);
+    // still counter clockwise from the same seam, with the flow of each side following it
+    assert!(counter_clockwise(&gcode));
+    let to = |gcode: &Parsed, i: usize| {
+        let v = gcode.vertices.get(&ids[i]).unwrap().to;
+        (v.x, v.y, v.e, v.f)
+    };
+    assert_eq!(to(&gcode, 1), (20.0, 10.0, 0.0, 1200.0));
+    assert_eq!(to(&gcode, 2), (20.0, 15.0, 0.5, 600.0));
+    assert_eq!(to(&gcode, 3), (10.0, 15.0, 1.0, 600.0));
+    assert_eq!(to(&gcode, 4), (10.0, 10.0, 0.5, 600.0));
+    assert_eq!(to(&gcode, 5), (20.0, 10.0, 1.0, 600.0));
+    assert_eq!(gcode.vertices.get(&ids[6]).unwrap().to.e, -1.0);
+    // stretching y twice over doubles the flow of the sides along y only
+    let scale = Affine::Scale(Vec3::new(1.0, 2.0, 1.0)).matrix(Vec3::new(0.0, 10.0, 0.0));
+    gcode.affine(&all, scale);
+    assert_eq!(to(&gcode, 2), (20.0, 20.0, 1.0, 600.0));
+    assert_eq!(to(&gcode, 3), (10.0, 20.0, 1.0, 600.0));
+    // shearing x by z leaves a planar layer flat
+    let shear = Affine::Shear {
+        xy: 0.0,
+        xz: 1.0,
+        yz: 0.0,
+    };
+    gcode.affine(&all, shear.matrix(Vec3::ZERO));
+    assert!((to(&gcode, 2).0 - 20.2).abs() < 1e-5);
+    assert_eq!(gcode.vertices.get(&ids[2]).unwrap().to.z, 0.2);
+}
+
+#[test]
+fn move_print_test() {
+    use super::bounds::Bed;
+    // a purge line, then a square with a wipe and the end gcode parking the head
+    let gcode = "G28\nG1 X0 Y0 Z0.2 F1200\nG1 X60 E5\nG1 Z1\nG1 X20 Y20\nG1 Z0.2\nG1 X40 E2\nG1 Y40 E2\nG1 X20 E2\nG1 Y20 E2\nG1 X25 E-0.5\nG1 E-1\nG1 Z10\nG1 X0 Y200";
+    let mut gcode = super::read(gcode, true).expect("failed to parse");
+    let ids = gcode.lines.clone();
+    assert_eq!(gcode.print_range(), Some((4, 12)));
+    // purging is found from the moves, not from the roles set when the file was read
+    let mut unclassified = gcode.clone();
+    for v in unclassified.vertices.values_mut() {
+        v.role = super::Role::Unknown;
     }
+    assert_eq!(unclassified.print_range(), Some((4, 12)));
+    let volume = BuildVolume {
+        bed: Bed::Rectangle {
+            x_min: 0.0,
+            y_min: 0.0,
+            x_max: 200.0,
+            y_max: 200.0,
+        },
+        height: 200.0,
+    };
+    let xy = |gcode: &Parsed, i: usize| {
+        let v = gcode.vertices.get(&ids[i]).unwrap().to;
+        ((v.x * 1e3).round() / 1e3, (v.y * 1e3).round() / 1e3, v.e)
+    };
+    let before = gcode.clone();
+    assert_eq!(gcode.move_print(100.0, 50.0, 90.0, &volume), Ok(9));
+    // purge and parking are untouched
+    assert_eq!(xy(&gcode, 2), (60.0, 0.0, 5.0));
+    assert_eq!(xy(&gcode, 3), (60.0, 0.0, 0.0));
+    assert_eq!(xy(&gcode, 13), (0.0, 200.0, 0.0));
+    // turned about (30, 30) and moved
+    assert_eq!(xy(&gcode, 4), (140.0, 70.0, 0.0));
+    assert_eq!(xy(&gcode, 6), (140.0, 90.0, 2.0));
+    assert_eq!(xy(&gcode, 10), (140.0, 75.0, -0.5));
+    assert_eq!(xy(&gcode, 12), (140.0, 75.0, 0.0));
+    let mut refused = gcode.clone();
+    let report = refused.move_print(100.0, 0.0, 0.0, &volume).unwrap_err();
+    assert_eq!(report.len(), 9);
+    assert_eq!(refused, gcode);
+    gcode.move_print(0.0, 0.0, -90.0, &volume).unwrap();
+    gcode.move_print(-100.0, -50.0, 0.0, &volume).unwrap();
+    assert_eq!(xy(&gcode, 7), xy(&before, 7));
 }
@@ -0,0 +1,178 @@
+use super::{Id, Label, Parsed, Pos};
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WipeParams {
+    // mm of the printed path to go back over
+    pub distance: f32,
+    // mm/s
+    pub speed: f32,
+}
+
+impl Parsed {
+    // xy points going back along the extrusions that end with id, staying in its shape
+    // returns them with the length they cover, which is less than distance on short paths
+    fn path_back(
+        &self,
+        id: &Id,
+        shape_of: &HashMap<Id, usize>,
+        distance: f32,
+    ) -> (Vec<(f32, f32)>, f32) {
+        let shape = shape_of.get(id);
+        let mut points = Vec::new();
+        let mut left = distance;
+        let mut v = self.vertices.get(id);
+        while let Some(vertex) = v.filter(|v| v.extrusion_move() && shape_of.get(&v.id) == shape) {
+            let from = vertex.get_from(self);
+            let (dx, dy) = (from.x - vertex.to.x, from.y - vertex.to.y);
+            let len = (dx * dx + dy * dy).sqrt();
+            if len >= left {
+                let t = left / len;
+                points.push((vertex.to.x + dx * t, vertex.to.y + dy * t));
+                left = 0.0;
+                break;
+            }
+            if len > f32::EPSILON {
+                points.push((from.x, from.y));
+                left -= len;
+            }
+            v = vertex.prev.and_then(|p| self.vertices.get(&p));
+        }
+        (points, distance - left)
+    }
+    // swaps retractions right after an extrusion for moves back along it that retract the
+    // same amount spread over their length, the moves up to the next travel start from where
+    // the wipe ends, firmware retractions are left alone
+    // returns how many retractions got a wipe
+    pub fn add_wipes(&mut self, params: &WipeParams) -> usize {
+        if params.distance <= 0.0 {
+            return 0;
+        }
+        let mut shape_of = HashMap::new();
+        for (i, shape) in self.shapes.iter().enumerate() {
+            for line in &shape.lines {
+                shape_of.insert(*line, i);
+            }
+        }
+        let retractions = self
+            .lines
+            .iter()
+            .filter(|id| {
+                self.vertices
+                    .get(id)
+                    .is_some_and(|v| v.label == Label::Retraction && v.to.e < 0.0)
+            })
+            .copied()
+            .collect::<Vec<Id>>();
+        let f = params.speed * 60.0;
+        let mut wipes: HashMap<Id, Vec<Id>> = HashMap::new();
+        for id in retractions {
+            let v = *self.vertices.get(&id).unwrap();
+            let Some(last) = v.prev.filter(|p| self.vertices[p].extrusion_move()) else {
+                continue;
+            };
+            // moves sitting where the retraction is, like a z lift, until the travel away
+            let mut stay = Vec::new();
+            let mut next = v.next.and_then(|n| self.vertices.get(&n));
+            while let Some(n) = next.filter(|n| (n.to.x, n.to.y) == (v.to.x, v.to.y)) {
+                stay.push(n.id);
+                next = n.next.and_then(|n| self.vertices.get(&n));
+            }
+            if !next.is_some_and(|n| n.to.e == 0.0) {
+                continue;
+            }
+            let (points, length) = self.path_back(&last, &shape_of, params.distance);
+            if length < f32::EPSILON {
+                continue;
+            }
+            let mut at = (v.to.x, v.to.y);
+            let mut new = Vec::new();
+            for point in points {
+                let len = ((point.0 - at.0).powi(2) + (point.1 - at.1).powi(2)).sqrt();
+                new.push(self.add_vertex(Pos {
+                    x: point.0,
+                    y: point.1,
+                    e: v.to.e * len / length,
+                    f,
+                    ..v.to
+                }));
+                at = point;
+            }
+            for s in stay {
+                let s = self.vertex_mut(&s).unwrap();
+                (s.to.x, s.to.y) = at;
+            }
+            wipes.insert(id, new);
+        }
+        let mut lines = Vec::with_capacity(self.lines.len() + wipes.len());
+        for line in &self.lines {
+            if let Some(new) = wipes.get(line) {
+                lines.extend(new);
+            } else {
+                lines.push(*line);
+            }
+        }
+        *self.lines_mut() = lines;
+        for id in wipes.keys() {
+            self.remove_vertex(id);
+        }
+        self.set_counts();
+        for id in wipes.values().flatten() {
+            self.relabel(id);
+            if let Some(next) = self.vertices[id].next {
+                self.relabel(&next);
+            }
+        }
+        self.assign_shapes();
+        wipes.len()
+    }
+}
+
+#[test]
+fn wipe_test() {
+    use super::emit::Emit;
+    // a corner, then a retraction, lift and travel away
+    let text = "G28\nG1 X10 Y10 Z0.2 F1200\nG1 X20 E1\nG1 Y20 E1\nG1 E-0.8 F2400\nG1 Z0.6 F600\nG1 X40 F6000\nG1 Z0.2\nG1 E0.8 F2400\nG1 X50 E1 F1200";
+    let mut gcode = super::read(text, true).expect("failed to parse");
+    let params = WipeParams {
+        distance: 15.0,
+        speed: 40.0,
+    };
+    assert_eq!(gcode.add_wipes(&params), 1);
+    let moves = gcode.lines[4..8]
+        .iter()
+        .map(|id| gcode.vertices[id])
+        .collect::<Vec<_>>();
+    assert_eq!(
+        moves.iter().map(|v| v.label).collect::<Vec<Label>>(),
+        [Label::Wipe, Label::Wipe, Label::LiftZ, Label::TravelMove]
+    );
+    // around the corner, with the retraction split 10 to 5
+    assert_eq!((moves[0].to.x, moves[0].to.y), (20.0, 10.0));
+    assert_eq!(
+        (moves[1].to.x, moves[1].to.y, moves[1].to.z),
+        (15.0, 10.0, 0.2)
+    );
+    assert!((moves[0].to.e + 0.8 * 10.0 / 15.0).abs() < 1e-5);
+    assert!((moves[1].to.e + 0.8 * 5.0 / 15.0).abs() < 1e-5);
+    let report = gcode.retraction_report();
+    assert!((report.total.retract.total - 0.8).abs() < 1e-5);
+    assert!((report.total.wipe.total - 15.0).abs() < 1e-5);
+    assert!((report.total.travel.total - 725f32.sqrt()).abs() < 1e-5);
+    let out = gcode.emit(&gcode, false);
+    assert!(out.contains(
+        "G1 Y20 E1 \nG1 Y10 E-0.53333336 F2400 \nG1 X15 E-0.26666668 \nG1 Z0.6 F600 \nG1 X40 Y20 F6000 \n"
+    ));
+    // no retractions are left to wipe
+    assert_eq!(gcode.add_wipes(&params), 0);
+    // the wipe stops where the path started
+    let mut gcode = super::read(text, true).expect("failed to parse");
+    let params = WipeParams {
+        distance: 50.0,
+        ..params
+    };
+    assert_eq!(gcode.add_wipes(&params), 1);
+    let end = gcode.vertices[&gcode.lines[5]].to;
+    assert_eq!((end.x, end.y), (10.0, 10.0));
+    assert_eq!(gcode.vertices[&gcode.lines[6]].label, Label::LiftZ);
+}
@@ -0,0 +1,171 @@
+use super::{
+    collision::{CollisionKind, CollisionParams},
+    Id, Label, Parsed, Pos,
+};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ZHopParams {
+    // travels at least this long in xy get a hop, 0 hops none for their length
+    pub min_travel: f32,
+    // also hop travels that drag the nozzle across printed material
+    pub crossing: bool,
+    // how far above printed material the nozzle has to be to clear it
+    pub clearance: f32,
+    pub height: f32,
+    // mm/s for the lift and the lower
+    pub speed: f32,
+}
+
+impl Parsed {
+    // consecutive travel moves in file order, instructions between them don't end a run
+    fn travel_runs(&self) -> Vec<Vec<Id>> {
+        let mut out = Vec::new();
+        let mut run = Vec::new();
+        for id in &self.lines {
+            let Some(v) = self.vertices.get(id) else {
+                continue;
+            };
+            if v.label == Label::TravelMove {
+                run.push(*id);
+            } else if !run.is_empty() {
+                out.push(std::mem::take(&mut run));
+            }
+        }
+        if !run.is_empty() {
+            out.push(run);
+        }
+        out
+    }
+    // lifts the nozzle before travels that are long or cross printed material and lowers it
+    // once they end, travels that already come after a lift are left alone
+    // returns how many hops were added
+    pub fn z_hop(&mut self, params: &ZHopParams) -> usize {
+        if params.height <= 0.0 {
+            return 0;
+        }
+        let crossing = if params.crossing {
+            let collision = CollisionParams {
+                clearance: params.clearance,
+                suggest: false,
+                z_hop: 0.0,
+            };
+            self.collisions(&collision)
+                .into_iter()
+                .filter(|c| matches!(c.kind, CollisionKind::TravelOverPart(_)))
+                .map(|c| c.id)
+                .collect()
+        } else {
+            HashSet::new()
+        };
+        let f = params.speed * 60.0;
+        // the lift goes before the first travel of a run and the lower after the last
+        let mut lifts = HashMap::new();
+        let mut lowers = HashMap::new();
+        for run in self.travel_runs() {
+            let first = self.vertices.get(&run[0]).unwrap();
+            let Some((label, from)) = first
+                .prev
+                .and_then(|p| self.vertices.get(&p))
+                .map(|p| (p.label, p.to))
+            else {
+                continue;
+            };
+            if label == Label::LiftZ || label == Label::Home {
+                continue;
+            }
+            let length = run
+                .iter()
+                .map(|id| {
+                    let v = self.vertices.get(id).unwrap();
+                    let from = v.get_from(self);
+                    ((v.to.x - from.x).powi(2) + (v.to.y - from.y).powi(2)).sqrt()
+                })
+                .sum::<f32>();
+            let long = params.min_travel > 0.0 && length >= params.min_travel;
+            if !long && !run.iter().any(|id| crossing.contains(id)) {
+                continue;
+            }
+            let lift = self.add_vertex(Pos {
+                z: from.z + params.height,
+                e: 0.0,
+                f,
+                ..from
+            });
+            for id in &run {
+                self.vertex_mut(id).unwrap().to.z += params.height;
+            }
+            let last = *run.last().unwrap();
+            let end = self.vertices.get(&last).unwrap().to;
+            let lower = self.add_vertex(Pos {
+                z: from.z,
+                e: 0.0,
+                f,
+                ..end
+            });
+            lifts.insert(run[0], lift);
+            lowers.insert(last, lower);
+        }
+        let mut lines = Vec::with_capacity(self.lines.len() + lifts.len() * 2);
+        for line in &self.lines {
+            lines.extend(lifts.get(line));
+            lines.push(*line);
+            lines.extend(lowers.get(line));
+        }
+        *self.lines_mut() = lines;
+        self.set_counts();
+        for id in lifts.values().chain(lowers.values()) {
+            self.relabel(id);
+        }
+        self.assign_shapes();
+        lifts.len()
+    }
+}
+
+#[test]
+fn z_hop_test() {
+    // a square, a travel across it in two legs, a long travel away and a short one
+    let gcode = "G28\nG1 X20 Y20 Z0.2 F3000\nG1 X40 E1\nG1 Y40 E1\nG1 X20 E1\nG1 Y20 E1\nG1 X10 Y30\nG1 X50\nG1 X52 E0.1\nG1 X100 Y100\nG1 X101 E0.03\nG1 X103";
+    let mut gcode = super::read(gcode, true).expect("failed to parse");
+    let shapes = gcode.shapes.len();
+    let travels = [gcode.lines[6], gcode.lines[7], gcode.lines[9]];
+    let mut params = ZHopParams {
+        min_travel: 0.0,
+        crossing: false,
+        clearance: 0.1,
+        height: 0.4,
+        speed: 30.0,
+    };
+    assert_eq!(gcode.z_hop(&params), 0);
+    params.min_travel = 60.0;
+    params.crossing = true;
+    assert_eq!(gcode.z_hop(&params), 2);
+    assert_eq!(gcode.lines.len(), 16);
+    assert_eq!(gcode.shapes.len(), shapes + 2);
+    let labels = gcode.lines[6..10]
+        .iter()
+        .map(|id| gcode.vertices.get(id).unwrap().label)
+        .collect::<Vec<Label>>();
+    assert_eq!(
+        labels,
+        [
+            Label::LiftZ,
+            Label::TravelMove,
+            Label::TravelMove,
+            Label::LowerZ
+        ]
+    );
+    for id in travels {
+        assert!((gcode.vertices.get(&id).unwrap().to.z - 0.6).abs() < 1e-5);
+    }
+    let lower = gcode.vertices.get(&gcode.lines[9]).unwrap().to;
+    assert_eq!(
+        (lower.x, lower.y, lower.z, lower.f),
+        (50.0, 30.0, 0.2, 1800.0)
+    );
+    // the short travel at the end stays down
+    let last = gcode.vertices.get(gcode.lines.last().unwrap()).unwrap();
+    assert_eq!((last.label, last.to.z), (Label::TravelMove, 0.2));
+    // hopped travels come after a lift now, so nothing changes the second time
+    assert_eq!(gcode.z_hop(&params), 0);
+}
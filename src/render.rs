@@ -1,8 +1,75 @@
 use super::{
-    print_analyzer::Label, settings::*, ForceRefresh, GCode, IdMap, PickableBundle, Tag, UiResource,
+    print_analyzer::{
+        bounds::{Bed, BuildVolume},
+        Label, Role, Support,
+    },
+    settings::*,
+    ui::ColorMode,
+    ForceRefresh, GCode, IdMap, LimitReport, PickableBundle, Tag, UiResource,
 };
 use bevy::prelude::*;
+use bevy::render::{
+    mesh::{Indices, PrimitiveTopology},
+    render_asset::RenderAssetUsages,
+};
+use std::collections::HashSet;
+
+// sphere drawn where a closed loop starts, not pickable
+#[derive(Component)]
+pub struct SeamMarker {
+    z: f32,
+}
+
+// mesh and transform of the print bed outline
+pub fn bed_mesh(volume: &BuildVolume) -> (Mesh, Transform) {
+    match &volume.bed {
+        Bed::Rectangle { .. } => {
+            let (x_min, y_min, x_max, y_max) = volume.extent();
+            let (w, l) = (x_max - x_min, y_max - y_min);
+            (
+                Cuboid::new(w, l, -0.1).into(),
+                Transform::from_xyz(x_min + w / 2.0, y_min + l / 2.0, 0.0),
+            )
+        }
+        Bed::Circle { x, y, radius } => (
+            Cylinder {
+                radius: *radius,
+                half_height: 0.05,
+            }
+            .into(),
+            Transform::from_xyz(*x, *y, -0.05)
+                .with_rotation(Quat::from_rotation_x(std::f32::consts::FRAC_PI_2)),
+        ),
+        Bed::Polygon(points) => {
+            // triangle fan, which is only correct for convex outlines
+            let positions = points
+                .iter()
+                .map(|(x, y)| [*x, *y, 0.0])
+                .collect::<Vec<[f32; 3]>>();
+            let normals = vec![[0.0, 0.0, 1.0]; positions.len()];
+            let mut indices = Vec::new();
+            for i in 1..points.len().saturating_sub(1) as u32 {
+                indices.extend([0, i, i + 1]);
+            }
+            let mesh = Mesh::new(
+                PrimitiveTopology::TriangleList,
+                RenderAssetUsages::default(),
+            )
+            .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+            .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals)
+            .with_inserted_indices(Indices::U32(indices));
+            (mesh, Transform::default())
+        }
+    }
+}
+
+fn lerp_color(a: Color, b: Color, t: f32) -> Color {
+    let (a, b) = (a.as_rgba_f32(), b.as_rgba_f32());
+    let c = |i: usize| a[i] + (b[i] - a[i]) * t;
+    Color::rgba(c(0), c(1), c(2), c(3))
+}
 
+#[allow(clippy::too_many_arguments)]
 pub fn render(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
@@ -10,12 +77,20 @@ pub fn render(
     mut map: ResMut<IdMap>,
     gcode: Res<GCode>,
     shapes: Query<Entity, With<Tag>>,
+    markers: Query<Entity, With<SeamMarker>>,
     settings: Res<Settings>,
+    mut ui_res: ResMut<UiResource>,
+    limit_report: Option<Res<LimitReport>>,
 ) {
-    for shape in shapes.iter() {
-        commands.entity(shape).despawn();
+    for entity in shapes.iter().chain(markers.iter()) {
+        commands.entity(entity).despawn();
     }
     let gcode = &gcode.0;
+    let beads = gcode.beads();
+    let over_limits = limit_report
+        .map(|r| r.0.iter().map(|v| v.id).collect::<HashSet<_>>())
+        .unwrap_or_default();
+    let mut flow_warnings = 0;
     let mut pos_list = Vec::new();
     for v in gcode.vertices.values() {
         let (xf, yf, zf) = (v.to.x, v.to.y, v.to.z);
@@ -28,26 +103,25 @@ pub fn render(
             }
         };
         let (start, end) = (Vec3::new(xi, yi, zi), Vec3::new(xf, yf, zf));
-        let dist = start.distance(end);
-        let flow = v.to.e / dist;
-        pos_list.push((v.id, start, end, flow, v.label));
+        pos_list.push((v.id, start, end, beads.get(&v.id), v.label, v.support));
     }
-    for (id, start, end, flow, label) in pos_list {
+    for (id, start, end, bead, label, support) in pos_list {
         if label == Label::FeedrateChangeOnly || label == Label::Home || label == Label::MysteryMove
         {
             continue;
         }
-        let radius = (flow / std::f32::consts::PI).sqrt();
+        let radius = bead.map_or(0.1, |b| b.width / 2.0);
+        let over_flow = bead.is_some_and(|b| b.flow > settings.max_volumetric_flow);
+        if over_flow {
+            flow_warnings += 1;
+        }
         let length = start.distance(end);
         let direction = end - start;
         let mut sphere = false;
 
         // Create the mesh and material
         let mesh_handle = {
-            if label == Label::PlanarExtrustion
-                || label == Label::NonPlanarExtrusion
-                || label == Label::PrePrintMove
-            {
+            if label == Label::PlanarExtrustion || label == Label::NonPlanarExtrusion {
                 meshes.add(Cylinder {
                     radius,
                     half_height: length / 2.0,
@@ -71,11 +145,35 @@ pub fn render(
             sphere = true;
         }
         let material_handle = match label {
-            Label::PlanarExtrustion | Label::NonPlanarExtrusion | Label::PrePrintMove => materials
-                .add(StandardMaterial {
+            _ if over_limits.contains(&id) => materials.add(StandardMaterial {
+                base_color: settings.limit_warning_color,
+                ..Default::default()
+            }),
+            _ if over_flow => materials.add(StandardMaterial {
+                base_color: settings.flow_warning_color,
+                ..Default::default()
+            }),
+            Label::PlanarExtrustion | Label::NonPlanarExtrusion
+                if ui_res.color_mode == ColorMode::Support =>
+            {
+                let base_color = match support {
+                    Support::Bridge => settings.bridge_color,
+                    Support::Overhang(p) => {
+                        lerp_color(settings.extrusion_color, settings.overhang_color, p / 100.0)
+                    }
+                    _ => settings.extrusion_color,
+                };
+                materials.add(StandardMaterial {
+                    base_color,
+                    ..Default::default()
+                })
+            }
+            Label::PlanarExtrustion | Label::NonPlanarExtrusion => {
+                materials.add(StandardMaterial {
                     base_color: settings.extrusion_color,
                     ..Default::default()
-                }),
+                })
+            }
             Label::TravelMove | Label::LiftZ | Label::LowerZ | Label::Wipe => {
                 materials.add(StandardMaterial {
                     base_color: settings.travel_color,
@@ -135,11 +233,32 @@ pub fn render(
         //     ));
         // }
     }
+    let seam_mesh = meshes.add(Sphere { radius: 0.4 });
+    let seam_material = materials.add(StandardMaterial {
+        base_color: settings.seam_color,
+        unlit: true,
+        ..Default::default()
+    });
+    for seam in gcode.seams() {
+        let (x, y, z) = seam.point;
+        commands.spawn((
+            PbrBundle {
+                mesh: seam_mesh.clone(),
+                material: seam_material.clone(),
+                transform: Transform::from_xyz(x, y, z),
+                visibility: Visibility::Hidden,
+                ..Default::default()
+            },
+            SeamMarker { z },
+        ));
+    }
+    ui_res.flow_warnings = flow_warnings;
     commands.remove_resource::<ForceRefresh>();
 }
 
 pub fn update_visibilities(
     mut entity_query: Query<(&Tag, &mut Visibility)>,
+    mut markers: Query<(&SeamMarker, &mut Visibility), Without<Tag>>,
     ui_res: Res<UiResource>,
     gcode: Res<GCode>,
 ) {
@@ -147,7 +266,9 @@ pub fn update_visibilities(
     for (tag, mut vis) in entity_query.iter_mut() {
         if let Some(v) = gcode.0.vertices.get(&tag.id) {
             let selected = match v.label {
-                Label::PrePrintMove => ui_res.vis_select.preprint,
+                Label::PlanarExtrustion | Label::NonPlanarExtrusion if v.role == Role::Purge => {
+                    ui_res.vis_select.preprint
+                }
                 Label::PlanarExtrustion | Label::NonPlanarExtrusion => ui_res.vis_select.extrusion,
                 Label::Retraction => ui_res.vis_select.retraction,
                 Label::DeRetraction => ui_res.vis_select.deretraction,
@@ -166,4 +287,14 @@ pub fn update_visibilities(
             }
         }
     }
+    for (marker, mut vis) in markers.iter_mut() {
+        *vis = if ui_res.vis_select.seams
+            && marker.z < ui_res.display_z_max.0
+            && marker.z > ui_res.display_z_min
+        {
+            Visibility::Visible
+        } else {
+            Visibility::Hidden
+        };
+    }
 }
@@ -1,7 +1,12 @@
+use crate::print_analyzer::{
+    bounds::{Bed, BuildVolume},
+    pause::PauseTemplate,
+};
 use bevy::prelude::{Color, KeyCode, MouseButton, Resource};
 use serde_json::{from_str, Value};
 use std::fs::{read_to_string, File};
 use std::io::Write;
+use std::sync::OnceLock;
 
 #[derive(Resource)]
 pub struct Settings {
@@ -13,12 +18,33 @@ pub struct Settings {
     pub retraction_color: Color,
     pub deretraction_color: Color,
     pub travel_color: Color,
+    pub flow_warning_color: Color,
+    pub limit_warning_color: Color,
+    pub overhang_color: Color,
+    pub bridge_color: Color,
+    pub seam_color: Color,
+    pub filament_diameter: f32,
+    pub max_volumetric_flow: f32,
+    pub max_feedrate: f32,
+    pub max_acceleration: f32,
+    pub build_volume: BuildVolume,
+    pub pause: PauseTemplate,
     pub save_suffix: String,
 }
 
+// look a key up in the user settings, falling back to the defaults so that
+// settings files written by older versions keep working when keys are added
+fn lookup<'a>(settings: &'a Value, section: &str, key: &str) -> &'a Value {
+    if let Some(value) = settings.get(section).and_then(|s| s.get(key)) {
+        return value;
+    }
+    static DEFAULTS: OnceLock<Value> = OnceLock::new();
+    let defaults = DEFAULTS.get_or_init(|| from_str(DEFAULT_SETTINGS).unwrap());
+    defaults.get(section).unwrap().get(key).unwrap()
+}
+
 fn read_key(settings: &Value, key: &str) -> KeyCode {
-    let value = settings.get("keys").unwrap();
-    let key = value.get(key).unwrap().as_str();
+    let key = lookup(settings, "keys", key).as_str();
     match key {
         Some("del") | Some("delete") => KeyCode::Delete,
         Some("backspace") => KeyCode::Backspace,
@@ -27,8 +53,7 @@ fn read_key(settings: &Value, key: &str) -> KeyCode {
 }
 
 fn read_mouse_button(settings: &Value, key: &str) -> MouseButton {
-    let value = settings.get("buttons").unwrap();
-    let button = value.get(key).unwrap().as_str();
+    let button = lookup(settings, "buttons", key).as_str();
     match button {
         Some("right") => MouseButton::Right,
         Some("left") => MouseButton::Left,
@@ -38,11 +63,71 @@ fn read_mouse_button(settings: &Value, key: &str) -> MouseButton {
 }
 
 fn read_color(settings: &Value, key: &str) -> Color {
-    let value = settings.get("colors").unwrap();
-    let color = value.get(key).unwrap().as_str().unwrap();
+    let color = lookup(settings, "colors", key).as_str().unwrap();
     Color::hex(color).unwrap()
 }
 
+fn read_f32(settings: &Value, section: &str, key: &str) -> f32 {
+    lookup(settings, section, key)
+        .as_f64()
+        .expect("invalid number option") as f32
+}
+
+fn read_point(point: &Value) -> (f32, f32) {
+    let point = point.as_array().expect("invalid build volume point");
+    let x = point[0].as_f64().expect("invalid build volume point") as f32;
+    let y = point[1].as_f64().expect("invalid build volume point") as f32;
+    (x, y)
+}
+
+fn read_build_volume(settings: &Value) -> BuildVolume {
+    let height = read_f32(settings, "build volume", "z");
+    let field = |key: &str| settings.get("build volume").and_then(|v| v.get(key));
+    let bed = match lookup(settings, "build volume", "shape").as_str() {
+        Some("rectangle") => {
+            let (x_min, y_min) = field("origin").map(read_point).unwrap_or((0.0, 0.0));
+            Bed::Rectangle {
+                x_min,
+                y_min,
+                x_max: x_min + read_f32(settings, "build volume", "x"),
+                y_max: y_min + read_f32(settings, "build volume", "y"),
+            }
+        }
+        Some("circle") => {
+            let (x, y) = field("center").map(read_point).unwrap_or((0.0, 0.0));
+            let radius = field("radius")
+                .and_then(Value::as_f64)
+                .expect("circular build volume needs a radius") as f32;
+            Bed::Circle { x, y, radius }
+        }
+        Some("polygon") => Bed::Polygon(
+            field("points")
+                .and_then(Value::as_array)
+                .expect("polygon build volume needs points")
+                .iter()
+                .map(read_point)
+                .collect(),
+        ),
+        _ => panic!("invalid build volume shape"),
+    };
+    BuildVolume { bed, height }
+}
+
+fn read_pause(settings: &Value) -> PauseTemplate {
+    let park = lookup(settings, "pause", "park");
+    PauseTemplate {
+        command: lookup(settings, "pause", "command")
+            .as_str()
+            .expect("invalid pause command")
+            .to_string(),
+        retract: read_f32(settings, "pause", "retract"),
+        retract_speed: read_f32(settings, "pause", "retract speed"),
+        z_lift: read_f32(settings, "pause", "z lift"),
+        park: (!park.is_null()).then(|| read_point(park)),
+        travel_speed: read_f32(settings, "pause", "travel speed"),
+    }
+}
+
 pub fn read_settings() -> Settings {
     let path = std::env::current_exe()
         .expect("could not find excecutable directory")
@@ -68,6 +153,17 @@ pub fn read_settings() -> Settings {
         retraction_color: read_color(&settings, "retraction color"),
         deretraction_color: read_color(&settings, "deretraction color"),
         travel_color: read_color(&settings, "travel move color"),
+        flow_warning_color: read_color(&settings, "flow warning color"),
+        limit_warning_color: read_color(&settings, "limit warning color"),
+        overhang_color: read_color(&settings, "overhang color"),
+        bridge_color: read_color(&settings, "bridge color"),
+        seam_color: read_color(&settings, "seam color"),
+        filament_diameter: read_f32(&settings, "printer", "filament diameter"),
+        max_volumetric_flow: read_f32(&settings, "printer", "max volumetric flow"),
+        max_feedrate: read_f32(&settings, "printer", "max feedrate"),
+        max_acceleration: read_f32(&settings, "printer", "max acceleration"),
+        build_volume: read_build_volume(&settings),
+        pause: read_pause(&settings),
         save_suffix: settings.get("save suffix").unwrap().to_string(),
     }
 }
@@ -77,7 +173,12 @@ const DEFAULT_SETTINGS: &str = r#"{
         "extrusion color": "ff0000",
         "retraction color" : "00ff00",
         "deretraction color": "000000",
-        "travel move color": "0000ff"
+        "travel move color": "0000ff",
+        "flow warning color": "ffff00",
+        "limit warning color": "ff00ff",
+        "overhang color": "ff8000",
+        "bridge color": "00ffff",
+        "seam color": "ffffff"
     },
     "keys" : {
         "hole delete": "del",
@@ -87,5 +188,25 @@ const DEFAULT_SETTINGS: &str = r#"{
         "mouse orbit": "right",
         "mouse pan": "left" 
     },
+    "printer" : {
+        "filament diameter": 1.75,
+        "max volumetric flow": 15.0,
+        "max feedrate": 12000.0,
+        "max acceleration": 3000.0
+    },
+    "build volume" : {
+        "shape": "rectangle",
+        "x": 300.0,
+        "y": 300.0,
+        "z": 300.0
+    },
+    "pause" : {
+        "command": "M600",
+        "retract": 5.0,
+        "retract speed": 40.0,
+        "z lift": 10.0,
+        "park": [0.0, 0.0],
+        "travel speed": 6000.0
+    },
     "save suffix": "_edited"
 }"#;
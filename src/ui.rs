@@ -1,8 +1,27 @@
-use super::diff::{SelectionLog, SetSelections};
+use super::diff::{EditName, GCodeLog, SelectionLog, SetSelections, UndoRedoGCode};
 use super::{
-    HoleDelete, MergeDelete, PickSelection, PickingPluginsSettings, Settings, SubdivideSelection,
+    AddWipes, AddZHops, AlignSeams, AnalyzeRetractions, AnalyzeSeams, ApplyAffine, ApplySchedule,
+    CheckCollisions, CheckLimits, ClampLimits, ClassifySupport, CollisionReport, DeformResult,
+    DeformSelection, ExportSvg, HoleDelete, InsertGCode, InsertPause, LimitReport, MergeDelete,
+    MovePrint, MovePrintWarning, PickSelection, PickingPluginsSettings, PreviewSchedule,
+    RetractionAnalysis, Save, SaveWarning, ScaleFlow, SchedulePreview, SeamAnalysis,
+    SeamsToSelection, SelectIds, SetFeedrate, SetRetractions, Settings, SlowUnsupported,
+    SubdivideSelection,
+};
+use crate::print_analyzer::{
+    bounds::{BoundsKind, OutOfBounds},
+    collision::{CollisionKind, CollisionParams, Fix},
+    feedrate::FeedrateOp,
+    limits::{LimitKind, Limits},
+    pause::PauseTemplate,
+    retraction::{RetractParams, RetractionStats},
+    schedule::{Parameter, Schedule},
+    seams::SeamAlignment,
+    transform::Affine,
+    wipe::WipeParams,
+    zhop::ZHopParams,
+    Parsed,
 };
-use crate::print_analyzer::Parsed;
 use crate::{ForceRefresh, GCode, Tag};
 use bevy::{prelude::*, window::PrimaryWindow};
 use bevy_egui::{EguiContext, EguiContexts};
@@ -17,6 +36,33 @@ pub enum Choice {
     Layer,
 }
 
+#[derive(PartialEq, Clone, Copy)]
+pub enum ColorMode {
+    Label,
+    Support,
+}
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum SurfaceKind {
+    Sine,
+    HeightMap,
+    Expression,
+}
+
+// inputs for the non-planar deformation, turned into a surface when it's applied
+pub struct DeformSettings {
+    pub kind: SurfaceKind,
+    // mm, for the sine wave and the height map
+    pub amplitude: f32,
+    pub wavelength: f32,
+    pub angle: f32,
+    // png file stretched over the extent
+    pub image: String,
+    pub extent: (f32, f32, f32, f32),
+    pub expression: String,
+    pub max_segment: f32,
+}
+
 #[derive(PartialEq)]
 enum Cursor {
     Pointer,
@@ -24,6 +70,20 @@ enum Cursor {
     Eraser,
 }
 
+// which of the tool windows are showing, toggled from the side panel
+#[derive(Default)]
+pub struct OpenWindows {
+    pub limits: bool,
+    pub collisions: bool,
+    pub retractions: bool,
+    pub seams: bool,
+    pub history: bool,
+    pub pause: bool,
+    pub schedule: bool,
+    pub transform: bool,
+    pub non_planar: bool,
+}
+
 #[derive(Resource)]
 pub struct UiResource {
     pub display_z_max: (f32, f32),
@@ -38,7 +98,34 @@ pub struct UiResource {
     pub rotate_y: f32,
     pub rotate_z: f32,
     pub scale: f32,
+    feedrate_op: FeedrateOp,
+    flow_factor: f32,
+    pub affine: Affine,
+    pub affine_preview: bool,
+    print_offset: (f32, f32),
+    print_angle: f32,
+    pub deform: DeformSettings,
+    pub flow_warnings: usize,
+    pub limits: Limits,
+    pub collision_params: CollisionParams,
+    z_hop: ZHopParams,
+    pub retract: RetractParams,
+    wipe: WipeParams,
+    retraction_by_shape: bool,
+    pub color_mode: ColorMode,
+    pub overhang_speed: f32,
+    pub overhang_fan: Option<f32>,
+    seam_alignment: SeamAlignment,
+    checkpoint_name: String,
+    pub pause: PauseTemplate,
+    pause_layers: String,
+    schedule_parameter: Parameter,
+    schedule_ramp: bool,
+    schedule_steps: String,
+    ramp_z: (f32, f32),
+    ramp_value: (f32, f32),
     cursor_enum: Cursor,
+    pub windows: OpenWindows,
 }
 
 impl Default for UiResource {
@@ -56,7 +143,73 @@ impl Default for UiResource {
             rotate_y: 0.0,
             rotate_z: 0.0,
             scale: 1.0,
+            feedrate_op: FeedrateOp::Multiply(1.0),
+            flow_factor: 1.0,
+            affine: Affine::Mirror { x: true, y: false },
+            affine_preview: false,
+            print_offset: (0.0, 0.0),
+            print_angle: 0.0,
+            deform: DeformSettings {
+                kind: SurfaceKind::Sine,
+                amplitude: 1.0,
+                wavelength: 20.0,
+                angle: 0.0,
+                image: String::new(),
+                extent: (0.0, 0.0, 100.0, 100.0),
+                expression: String::from("0.5 * sin(x / 5) * cos(y / 5)"),
+                max_segment: 1.0,
+            },
+            flow_warnings: 0,
+            limits: Limits {
+                max_flow: f32::INFINITY,
+                max_feedrate: f32::INFINITY,
+                max_accel: f32::INFINITY,
+            },
+            collision_params: CollisionParams {
+                clearance: 0.1,
+                suggest: true,
+                z_hop: 0.4,
+            },
+            z_hop: ZHopParams {
+                min_travel: 20.0,
+                crossing: true,
+                clearance: 0.1,
+                height: 0.4,
+                speed: 10.0,
+            },
+            retract: RetractParams {
+                min_travel: 2.0,
+                length: 0.8,
+                speed: 35.0,
+                extra_restart: 0.0,
+                firmware: false,
+            },
+            wipe: WipeParams {
+                distance: 2.0,
+                speed: 40.0,
+            },
+            retraction_by_shape: false,
+            color_mode: ColorMode::Label,
+            overhang_speed: 15.0,
+            overhang_fan: None,
+            seam_alignment: SeamAlignment::Rear,
+            checkpoint_name: String::new(),
+            pause: PauseTemplate {
+                command: String::from("M600"),
+                retract: 0.0,
+                retract_speed: 40.0,
+                z_lift: 0.0,
+                park: None,
+                travel_speed: 6000.0,
+            },
+            pause_layers: String::new(),
+            schedule_parameter: Parameter::Temperature,
+            schedule_ramp: false,
+            schedule_steps: String::new(),
+            ramp_z: (0.0, 10.0),
+            ramp_value: (220.0, 190.0),
             cursor_enum: Cursor::Pointer,
+            windows: OpenWindows::default(),
         }
     }
 }
@@ -68,6 +221,7 @@ pub struct VisibilitySelector {
     pub deretraction: bool,
     pub travel: bool,
     pub preprint: bool,
+    pub seams: bool,
 }
 impl Default for VisibilitySelector {
     fn default() -> Self {
@@ -78,11 +232,18 @@ impl Default for VisibilitySelector {
             deretraction: false,
             travel: false,
             preprint: false,
+            seams: false,
         }
     }
 }
 
-pub fn ui_setup(gcode: Res<GCode>, mut ui_res: ResMut<UiResource>) {
+pub fn ui_setup(gcode: Res<GCode>, mut ui_res: ResMut<UiResource>, settings: Res<Settings>) {
+    ui_res.limits = Limits {
+        max_flow: settings.max_volumetric_flow,
+        max_feedrate: settings.max_feedrate,
+        max_accel: settings.max_acceleration,
+    };
+    ui_res.pause = settings.pause.clone();
     for (_, v) in gcode.0.vertices.iter() {
         ui_res.display_z_max.1 = ui_res.display_z_max.1.max(v.to.z);
         ui_res.vertex_counter = ui_res.vertex_counter.max(v.count);
@@ -163,6 +324,24 @@ pub fn ui_system(
         .show(contexts.ctx_mut(), |ui| {
             egui::ScrollArea::vertical().show(ui, |ui| {
                 ui.label("world");
+                ui.horizontal_wrapped(|ui| {
+                    let windows = &mut ui_res.windows;
+                    ui.toggle_value(&mut windows.limits, "Limits");
+                    ui.toggle_value(&mut windows.collisions, "Collisions");
+                    ui.toggle_value(&mut windows.retractions, "Retractions");
+                    ui.toggle_value(&mut windows.seams, "Seams");
+                    ui.toggle_value(&mut windows.history, "History");
+                    ui.toggle_value(&mut windows.pause, "Pause");
+                    ui.toggle_value(&mut windows.schedule, "Schedule");
+                    ui.toggle_value(&mut windows.transform, "Transform");
+                    ui.toggle_value(&mut windows.non_planar, "Non-planar");
+                });
+                if ui_res.flow_warnings > 0 {
+                    ui.colored_label(
+                        egui::Color32::YELLOW,
+                        format!("{} moves exceed max volumetric flow", ui_res.flow_warnings),
+                    );
+                }
                 ui.add_space(spacing);
                 ui.add(egui::Slider::new(&mut ui_res.vertex_counter, 0..=max));
                 ui.add_space(spacing);
@@ -237,6 +416,38 @@ pub fn ui_system(
                     let _ = ui.checkbox(&mut ui_res.vis_select.wipe, "wipe");
                     let _ = ui.checkbox(&mut ui_res.vis_select.deretraction, "deretraction");
                     let _ = ui.checkbox(&mut ui_res.vis_select.preprint, "preprint");
+                    let _ = ui.checkbox(&mut ui_res.vis_select.seams, "seams");
+                });
+                ui.add_space(spacing);
+                ui.horizontal(|ui| {
+                    ui.label("color by");
+                    let by_label =
+                        ui.radio_value(&mut ui_res.color_mode, ColorMode::Label, "label");
+                    let by_support =
+                        ui.radio_value(&mut ui_res.color_mode, ColorMode::Support, "support");
+                    if by_support.changed() {
+                        commands.init_resource::<ClassifySupport>();
+                    } else if by_label.changed() {
+                        commands.init_resource::<ForceRefresh>();
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.add(
+                        egui::DragValue::new(&mut ui_res.overhang_speed)
+                            .clamp_range(1.0..=500.0)
+                            .suffix(" mm/s"),
+                    )
+                    .on_hover_text("top speed for overhangs and bridges");
+                    let mut fan = ui_res.overhang_fan.is_some();
+                    ui.checkbox(&mut fan, "fan");
+                    let mut speed = ui_res.overhang_fan.unwrap_or(255.0);
+                    if fan {
+                        ui.add(egui::DragValue::new(&mut speed).clamp_range(0.0..=255.0));
+                    }
+                    ui_res.overhang_fan = fan.then_some(speed);
+                    if ui.button("Slow unsupported").clicked() {
+                        commands.init_resource::<SlowUnsupported>();
+                    }
                 });
                 ui.add_space(spacing);
                 ui.horizontal(|ui| {
@@ -251,6 +462,7 @@ pub fn ui_system(
                         let x = params.next().unwrap().parse::<f32>().unwrap();
                         let y = params.next().unwrap().parse::<f32>().unwrap();
                         let z = params.next().unwrap().parse::<f32>().unwrap();
+                        let mut count = selection.len();
                         match enu {
                             Choice::Vertex => {
                                 for selection in &selection {
@@ -266,6 +478,7 @@ pub fn ui_system(
                                 for vertex in shapes.iter() {
                                     gcode.0.translate(vertex, x, y, z);
                                 }
+                                count = shapes.len();
                             }
                             Choice::Layer => {
                                 let mut layers = HashSet::new();
@@ -276,8 +489,13 @@ pub fn ui_system(
                                 for vertex in layers.iter() {
                                     gcode.0.translate(vertex, x, y, z);
                                 }
+                                count = layers.len();
                             }
                         }
+                        commands.insert_resource(EditName(format!(
+                            "Translate {} vertices by ({},{},{})",
+                            count, x, y, z
+                        )));
                         commands.init_resource::<ForceRefresh>();
                     }
                 });
@@ -290,6 +508,14 @@ pub fn ui_system(
                 ui.add_space(spacing);
                 ui.text_edit_multiline(&mut ui_res.gcode_emit)
                     .on_hover_text("enter custom gcode");
+                ui.horizontal(|ui| {
+                    if ui.button("Insert before").clicked() && !selection.is_empty() {
+                        commands.insert_resource(InsertGCode { after: false });
+                    }
+                    if ui.button("Insert after").clicked() && !selection.is_empty() {
+                        commands.insert_resource(InsertGCode { after: true });
+                    }
+                });
                 ui.add_space(spacing);
                 ui.horizontal(|ui| {
                     ui.add(egui::Slider::new(&mut ui_res.rotate_x, -180.0..=180.0).vertical());
@@ -297,15 +523,17 @@ pub fn ui_system(
                     ui.add(egui::Slider::new(&mut ui_res.rotate_z, -180.0..=180.0).vertical());
                     if ui.button("Rotate").clicked() {
                         let origin = gcode.0.get_centroid(&selection);
-                        for vertex in &selection {
-                            gcode.0.rotate(
-                                vertex,
-                                origin,
-                                ui_res.rotate_x,
-                                ui_res.rotate_y,
-                                ui_res.rotate_z,
-                            );
-                        }
+                        let count = gcode.0.rotate(
+                            &selection,
+                            origin,
+                            ui_res.rotate_x,
+                            ui_res.rotate_y,
+                            ui_res.rotate_z,
+                        );
+                        commands.insert_resource(EditName(format!(
+                            "Rotate {} vertices by ({},{},{})",
+                            count, ui_res.rotate_x, ui_res.rotate_y, ui_res.rotate_z
+                        )));
                         commands.init_resource::<ForceRefresh>();
                     }
                 });
@@ -314,19 +542,970 @@ pub fn ui_system(
                     ui.add(egui::Slider::new(&mut ui_res.scale, 0.1..=10.0));
                     if ui.button("Scale").clicked() {
                         let origin = gcode.0.get_centroid(&selection);
-                        for vertex in &selection {
-                            gcode.0.scale(vertex, origin, ui_res.scale);
-                        }
+                        let count = gcode.0.scale(&selection, origin, ui_res.scale);
+                        commands.insert_resource(EditName(format!(
+                            "Scale {} vertices by {}",
+                            count, ui_res.scale
+                        )));
                         commands.init_resource::<ForceRefresh>();
                     }
                 });
+                ui.add_space(spacing);
+                ui.horizontal(|ui| {
+                    let op = &mut ui_res.feedrate_op;
+                    if ui
+                        .radio(matches!(op, FeedrateOp::Multiply(_)), "multiply")
+                        .clicked()
+                    {
+                        *op = FeedrateOp::Multiply(1.0);
+                    }
+                    if ui.radio(matches!(op, FeedrateOp::Set(_)), "set").clicked() {
+                        *op = FeedrateOp::Set(1800.0);
+                    }
+                    if ui
+                        .radio(matches!(op, FeedrateOp::Clamp(..)), "clamp")
+                        .clicked()
+                    {
+                        *op = FeedrateOp::Clamp(600.0, 6000.0);
+                    }
+                });
+                ui.horizontal(|ui| {
+                    match &mut ui_res.feedrate_op {
+                        FeedrateOp::Multiply(factor) => {
+                            ui.add(
+                                egui::DragValue::new(factor)
+                                    .speed(0.01)
+                                    .clamp_range(0.01..=10.0),
+                            );
+                        }
+                        FeedrateOp::Set(f) => {
+                            ui.add(
+                                egui::DragValue::new(f)
+                                    .clamp_range(1.0..=f32::MAX)
+                                    .suffix(" mm/min"),
+                            );
+                        }
+                        FeedrateOp::Clamp(min, max) => {
+                            ui.add(
+                                egui::DragValue::new(min)
+                                    .clamp_range(1.0..=*max)
+                                    .suffix(" mm/min"),
+                            );
+                            ui.add(
+                                egui::DragValue::new(max)
+                                    .clamp_range(*min..=f32::MAX)
+                                    .suffix(" mm/min"),
+                            );
+                        }
+                    }
+                    if ui.button("Set speed").clicked() && !selection.is_empty() {
+                        commands.insert_resource(SetFeedrate(ui_res.feedrate_op));
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.add(
+                        egui::DragValue::new(&mut ui_res.flow_factor)
+                            .speed(0.01)
+                            .clamp_range(0.0..=5.0),
+                    );
+                    if ui.button("Scale flow").clicked() && !selection.is_empty() {
+                        commands.insert_resource(ScaleFlow(ui_res.flow_factor));
+                    }
+                });
                 if ui.button("Save").clicked() {
-                    let _ = gcode.0.write_to_file("./test_output.gcode");
+                    commands.init_resource::<Save>();
                 }
+                ui.horizontal(|ui| {
+                    if ui.button("Export layer SVG").clicked() {
+                        commands.insert_resource(ExportSvg { all: false });
+                    }
+                    if ui.button("Export all layers").clicked() {
+                        commands.insert_resource(ExportSvg { all: true });
+                    }
+                });
             })
         });
 }
 
+pub fn limits_window(
+    mut contexts: EguiContexts,
+    mut commands: Commands,
+    mut ui_res: ResMut<UiResource>,
+    report: Option<Res<LimitReport>>,
+) {
+    let mut open = ui_res.windows.limits;
+    let window = egui::Window::new("Limits").open(&mut open);
+    window.show(contexts.ctx_mut(), |ui| {
+        let limits = &mut ui_res.limits;
+        ui.horizontal(|ui| {
+            ui.add(egui::DragValue::new(&mut limits.max_flow).suffix(" mm³/s"));
+            ui.label("max flow");
+        });
+        ui.horizontal(|ui| {
+            ui.add(egui::DragValue::new(&mut limits.max_feedrate).suffix(" mm/min"));
+            ui.label("max feedrate");
+        });
+        ui.horizontal(|ui| {
+            ui.add(egui::DragValue::new(&mut limits.max_accel).suffix(" mm/s²"));
+            ui.label("max acceleration");
+        });
+        ui.horizontal(|ui| {
+            if ui.button("Check").clicked() {
+                commands.init_resource::<CheckLimits>();
+            }
+            if ui.button("Clamp feedrates").clicked() {
+                commands.init_resource::<ClampLimits>();
+            }
+        });
+        let Some(report) = report else {
+            return;
+        };
+        ui.label(format!("{} violations", report.0.len()));
+        let row_height = ui.text_style_height(&egui::TextStyle::Body);
+        egui::ScrollArea::vertical().max_height(200.0).show_rows(
+            ui,
+            row_height,
+            report.0.len(),
+            |ui, rows| {
+                for v in &report.0[rows] {
+                    let (kind, unit) = match v.kind {
+                        LimitKind::Flow => ("flow", "mm³/s"),
+                        LimitKind::Feedrate => ("feedrate", "mm/min"),
+                        LimitKind::Acceleration => ("acceleration", "mm/s²"),
+                    };
+                    ui.label(format!(
+                        "line {} layer {}: {} over by {:.2} {}",
+                        v.line, v.layer, kind, v.over, unit
+                    ));
+                }
+            },
+        );
+    });
+    ui_res.windows.limits = open;
+}
+
+pub fn collisions_window(
+    mut contexts: EguiContexts,
+    mut commands: Commands,
+    mut ui_res: ResMut<UiResource>,
+    report: Option<Res<CollisionReport>>,
+) {
+    let mut open = ui_res.windows.collisions;
+    let window = egui::Window::new("Collisions").open(&mut open);
+    window.show(contexts.ctx_mut(), |ui| {
+        let params = &mut ui_res.collision_params;
+        ui.horizontal(|ui| {
+            ui.add(
+                egui::DragValue::new(&mut params.clearance)
+                    .speed(0.01)
+                    .suffix(" mm"),
+            );
+            ui.label("clearance");
+        });
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut params.suggest, "suggest fixes");
+            ui.add(
+                egui::DragValue::new(&mut params.z_hop)
+                    .speed(0.01)
+                    .suffix(" mm"),
+            );
+            ui.label("z-hop");
+        });
+        ui.horizontal(|ui| {
+            if ui.button("Check").clicked() {
+                commands.init_resource::<CheckCollisions>();
+            }
+            if let Some(report) = &report {
+                if ui.button("Select flagged").clicked() {
+                    commands.insert_resource(SelectIds(report.0.iter().map(|c| c.id).collect()));
+                }
+            }
+        });
+        ui.separator();
+        let res = ui_res.as_mut();
+        let hop = &mut res.z_hop;
+        ui.horizontal(|ui| {
+            ui.add(
+                egui::DragValue::new(&mut hop.min_travel)
+                    .speed(0.5)
+                    .clamp_range(0.0..=f32::MAX)
+                    .suffix(" mm"),
+            );
+            ui.label("min travel");
+            ui.checkbox(&mut hop.crossing, "crossing printed");
+        });
+        ui.horizontal(|ui| {
+            ui.add(
+                egui::DragValue::new(&mut hop.height)
+                    .speed(0.01)
+                    .clamp_range(0.0..=10.0)
+                    .suffix(" mm"),
+            );
+            ui.add(
+                egui::DragValue::new(&mut hop.speed)
+                    .clamp_range(1.0..=f32::MAX)
+                    .suffix(" mm/s"),
+            );
+            if ui.button("Add z-hops").clicked() {
+                commands.insert_resource(AddZHops(ZHopParams {
+                    clearance: res.collision_params.clearance,
+                    ..*hop
+                }));
+            }
+        });
+        let Some(report) = report else {
+            return;
+        };
+        ui.label(format!("{} collisions", report.0.len()));
+        let row_height = ui.text_style_height(&egui::TextStyle::Body);
+        egui::ScrollArea::vertical().max_height(200.0).show_rows(
+            ui,
+            row_height,
+            report.0.len(),
+            |ui, rows| {
+                for c in &report.0[rows] {
+                    let kind = match c.kind {
+                        CollisionKind::TravelOverPart(n) => format!("travel crosses {} moves", n),
+                        CollisionKind::BelowPrinted(d) => format!("{:.2}mm below printed", d),
+                    };
+                    let fix = match &c.fix {
+                        Some(Fix::ZHop(h)) => format!(", z-hop {:.2}mm", h),
+                        Some(Fix::Reroute(path)) => format!(", reroute via {:?}", path),
+                        None => String::new(),
+                    };
+                    ui.label(format!("line {}: {}{}", c.line, kind, fix));
+                }
+            },
+        );
+    });
+    ui_res.windows.collisions = open;
+}
+
+pub fn retraction_window(
+    mut contexts: EguiContexts,
+    mut commands: Commands,
+    mut ui_res: ResMut<UiResource>,
+    analysis: Option<Res<RetractionAnalysis>>,
+) {
+    let mut open = ui_res.windows.retractions;
+    let window = egui::Window::new("Retractions").open(&mut open);
+    window.show(contexts.ctx_mut(), |ui| {
+        let params = &mut ui_res.retract;
+        ui.horizontal(|ui| {
+            ui.add(
+                egui::DragValue::new(&mut params.min_travel)
+                    .speed(0.1)
+                    .clamp_range(0.0..=f32::MAX)
+                    .suffix(" mm"),
+            );
+            ui.label("min travel");
+            ui.checkbox(&mut params.firmware, "G10/G11");
+        });
+        ui.horizontal(|ui| {
+            ui.add_enabled(
+                !params.firmware,
+                egui::DragValue::new(&mut params.length)
+                    .speed(0.01)
+                    .clamp_range(0.0..=20.0)
+                    .suffix(" mm"),
+            );
+            ui.add_enabled(
+                !params.firmware,
+                egui::DragValue::new(&mut params.speed)
+                    .clamp_range(1.0..=f32::MAX)
+                    .suffix(" mm/s"),
+            );
+            ui.add(
+                egui::DragValue::new(&mut params.extra_restart)
+                    .speed(0.01)
+                    .clamp_range(0.0..=5.0)
+                    .suffix(" mm"),
+            );
+            ui.label("extra restart");
+        });
+        if ui.button("Set retractions").clicked() {
+            commands.insert_resource(SetRetractions(*params));
+        }
+        let wipe = &mut ui_res.wipe;
+        ui.horizontal(|ui| {
+            ui.add(
+                egui::DragValue::new(&mut wipe.distance)
+                    .speed(0.1)
+                    .clamp_range(0.0..=f32::MAX)
+                    .suffix(" mm"),
+            );
+            ui.add(
+                egui::DragValue::new(&mut wipe.speed)
+                    .clamp_range(1.0..=f32::MAX)
+                    .suffix(" mm/s"),
+            );
+            if ui.button("Add wipes").clicked() {
+                commands.insert_resource(AddWipes(*wipe));
+            }
+        });
+        ui.separator();
+        ui.horizontal(|ui| {
+            if ui.button("Analyze").clicked() {
+                commands.init_resource::<AnalyzeRetractions>();
+            }
+            if let Some(analysis) = &analysis {
+                if ui.button("Export JSON").clicked() {
+                    let json = analysis.0.to_json().to_string();
+                    if std::fs::write("./retraction_report.json", json).is_ok() {
+                        println!("retraction report saved");
+                    }
+                }
+            }
+        });
+        let Some(analysis) = analysis else {
+            return;
+        };
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut ui_res.retraction_by_shape, false, "per layer");
+            ui.radio_value(&mut ui_res.retraction_by_shape, true, "per shape");
+        });
+        let rows = if ui_res.retraction_by_shape {
+            analysis
+                .0
+                .shapes
+                .iter()
+                .map(|(id, stats)| (format!("{:?}", id), stats))
+                .collect::<Vec<(String, &RetractionStats)>>()
+        } else {
+            analysis
+                .0
+                .layers
+                .iter()
+                .map(|(z, stats)| (format!("z {:.2}", z), stats))
+                .collect()
+        };
+        let mut rows = vec![(String::from("total"), &analysis.0.total)]
+            .into_iter()
+            .chain(rows)
+            .collect::<Vec<_>>();
+        rows.retain(|(_, s)| s.retract.count + s.deretract.count + s.z_hop.count > 0);
+        let row_height = ui.text_style_height(&egui::TextStyle::Body);
+        egui::ScrollArea::vertical().max_height(300.0).show_rows(
+            ui,
+            row_height,
+            rows.len(),
+            |ui, range| {
+                egui::Grid::new("retraction table")
+                    .striped(true)
+                    .show(ui, |ui| {
+                        for header in [
+                            "",
+                            "retracts",
+                            "length",
+                            "speed",
+                            "unretracts",
+                            "extra",
+                            "z-hops",
+                            "height",
+                            "wipes",
+                            "travel",
+                        ] {
+                            ui.label(header);
+                        }
+                        ui.end_row();
+                        for (name, s) in &rows[range] {
+                            ui.label(name);
+                            ui.label(s.retract.count.to_string());
+                            ui.label(format!("{:.2}mm", s.retract.mean()));
+                            ui.label(format!("{:.0}mm/s", s.retract_speed.mean()));
+                            ui.label(s.deretract.count.to_string());
+                            ui.label(format!("{:.3}mm", s.unretract_extra.mean()));
+                            ui.label(s.z_hop.count.to_string());
+                            ui.label(format!("{:.2}mm", s.z_hop.mean()));
+                            ui.label(s.wipe.count.to_string());
+                            ui.label(format!("{:.1}mm", s.travel.mean()));
+                            ui.end_row();
+                        }
+                    });
+            },
+        );
+    });
+    ui_res.windows.retractions = open;
+}
+
+pub fn pause_window(
+    mut contexts: EguiContexts,
+    mut commands: Commands,
+    mut ui_res: ResMut<UiResource>,
+    gcode: Res<GCode>,
+) {
+    let mut open = ui_res.windows.pause;
+    let window = egui::Window::new("Pause").open(&mut open);
+    window.show(contexts.ctx_mut(), |ui| {
+        ui.horizontal(|ui| {
+            ui.text_edit_singleline(&mut ui_res.pause.command);
+            for preset in ["M600", "PAUSE", "M0"] {
+                if ui.small_button(preset).clicked() {
+                    ui_res.pause.command = preset.to_string();
+                }
+            }
+        });
+        let pause = &mut ui_res.pause;
+        ui.horizontal(|ui| {
+            ui.add(
+                egui::DragValue::new(&mut pause.retract)
+                    .speed(0.1)
+                    .suffix(" mm"),
+            );
+            ui.label("retract");
+            ui.add(egui::DragValue::new(&mut pause.retract_speed).suffix(" mm/s"));
+        });
+        ui.horizontal(|ui| {
+            ui.add(
+                egui::DragValue::new(&mut pause.z_lift)
+                    .speed(0.1)
+                    .suffix(" mm"),
+            );
+            ui.label("z lift");
+        });
+        ui.horizontal(|ui| {
+            let mut park = pause.park.is_some();
+            ui.checkbox(&mut park, "park");
+            let (mut x, mut y) = pause.park.unwrap_or((0.0, 0.0));
+            if park {
+                ui.add(egui::DragValue::new(&mut x).prefix("x "));
+                ui.add(egui::DragValue::new(&mut y).prefix("y "));
+                ui.add(egui::DragValue::new(&mut pause.travel_speed).suffix(" mm/min"));
+            }
+            pause.park = park.then_some((x, y));
+        });
+        ui.horizontal(|ui| {
+            ui.text_edit_singleline(&mut ui_res.pause_layers)
+                .on_hover_text("layer heights, separated by spaces");
+            if ui.button("current layer").clicked() {
+                let top = ui_res.display_z_max.0;
+                if let Some(z) = gcode.0.layers().into_iter().rev().find(|z| *z <= top) {
+                    ui_res.pause_layers =
+                        format!("{} {}", ui_res.pause_layers, z).trim().to_string();
+                }
+            }
+        });
+        if ui.button("Insert pauses").clicked() {
+            let layers = ui_res
+                .pause_layers
+                .split([' ', ','])
+                .filter_map(|z| z.parse::<f32>().ok())
+                .collect::<Vec<f32>>();
+            if !layers.is_empty() {
+                commands.insert_resource(InsertPause(layers));
+            }
+        }
+    });
+    ui_res.windows.pause = open;
+}
+
+pub fn history_window(
+    mut contexts: EguiContexts,
+    mut commands: Commands,
+    mut ui_res: ResMut<UiResource>,
+    mut log: ResMut<GCodeLog>,
+) {
+    let mut open = ui_res.windows.history;
+    let window = egui::Window::new("History").open(&mut open);
+    window.show(contexts.ctx_mut(), |ui| {
+        let position = log.position();
+        let mut jump = None;
+        ui.horizontal(|ui| {
+            ui.text_edit_singleline(&mut ui_res.checkpoint_name);
+            if ui.button("Add checkpoint").clicked() && !ui_res.checkpoint_name.is_empty() {
+                log.add_checkpoint(std::mem::take(&mut ui_res.checkpoint_name));
+            }
+        });
+        let mut remove = None;
+        for (i, (name, state)) in log.checkpoints().iter().enumerate() {
+            ui.horizontal(|ui| {
+                if ui.selectable_label(*state == position, name).clicked() {
+                    jump = Some(*state);
+                }
+                if ui.small_button("x").clicked() {
+                    remove = Some(i);
+                }
+            });
+        }
+        if let Some(i) = remove {
+            log.remove_checkpoint(i);
+        }
+        ui.separator();
+        // the opened file is state 0, every edit after it is one more
+        let entries = std::iter::once("Open file")
+            .chain(log.descriptions())
+            .collect::<Vec<&str>>();
+        let row_height = ui.text_style_height(&egui::TextStyle::Body);
+        egui::ScrollArea::vertical()
+            .max_height(300.0)
+            .stick_to_bottom(true)
+            .show_rows(ui, row_height, entries.len(), |ui, range| {
+                for state in range {
+                    // undone edits stay listed until a new edit replaces them
+                    let text = if state > position {
+                        egui::RichText::new(entries[state]).weak()
+                    } else {
+                        egui::RichText::new(entries[state])
+                    };
+                    if ui.selectable_label(state == position, text).clicked() {
+                        jump = Some(state);
+                    }
+                }
+            });
+        if let Some(state) = jump {
+            log.jump(state);
+            commands.init_resource::<UndoRedoGCode>();
+        }
+    });
+    ui_res.windows.history = open;
+}
+
+pub fn schedule_window(
+    mut contexts: EguiContexts,
+    mut commands: Commands,
+    mut ui_res: ResMut<UiResource>,
+    preview: Option<Res<SchedulePreview>>,
+) {
+    let mut open = ui_res.windows.schedule;
+    let window = egui::Window::new("Layer schedule").open(&mut open);
+    window.show(contexts.ctx_mut(), |ui| {
+        ui.horizontal(|ui| {
+            for param in [
+                Parameter::Temperature,
+                Parameter::Fan,
+                Parameter::Flow,
+                Parameter::Speed,
+            ] {
+                ui.radio_value(&mut ui_res.schedule_parameter, param, param.name());
+            }
+        });
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut ui_res.schedule_ramp, false, "steps");
+            ui.radio_value(&mut ui_res.schedule_ramp, true, "ramp");
+        });
+        if ui_res.schedule_ramp {
+            let res = ui_res.as_mut();
+            let (z, value) = (&mut res.ramp_z, &mut res.ramp_value);
+            ui.horizontal(|ui| {
+                ui.add(egui::DragValue::new(&mut z.0).speed(0.1).prefix("from z "));
+                ui.add(egui::DragValue::new(&mut value.0).prefix("at "));
+            });
+            ui.horizontal(|ui| {
+                ui.add(egui::DragValue::new(&mut z.1).speed(0.1).prefix("to z "));
+                ui.add(egui::DragValue::new(&mut value.1).prefix("at "));
+            });
+        } else {
+            ui.text_edit_singleline(&mut ui_res.schedule_steps)
+                .on_hover_text("layers and values, e.g. 0-20: 215, 21-40: 210");
+        }
+        let schedule = if ui_res.schedule_ramp {
+            Ok(Schedule::Ramp {
+                z: ui_res.ramp_z,
+                value: ui_res.ramp_value,
+            })
+        } else {
+            Schedule::parse_steps(&ui_res.schedule_steps)
+        };
+        match schedule {
+            Ok(schedule) => {
+                ui.horizontal(|ui| {
+                    if ui.button("Preview").clicked() {
+                        commands.insert_resource(PreviewSchedule(
+                            ui_res.schedule_parameter,
+                            schedule.clone(),
+                        ));
+                    }
+                    if ui.button("Apply").clicked() {
+                        commands
+                            .insert_resource(ApplySchedule(ui_res.schedule_parameter, schedule));
+                    }
+                });
+            }
+            Err(e) => {
+                ui.label(e.to_string());
+            }
+        }
+        let Some(preview) = preview else {
+            return;
+        };
+        let value = |v: Option<f32>| v.map_or(String::from("-"), |v| v.to_string());
+        let row_height = ui.text_style_height(&egui::TextStyle::Body);
+        egui::ScrollArea::vertical().max_height(300.0).show_rows(
+            ui,
+            row_height,
+            preview.0.len(),
+            |ui, range| {
+                egui::Grid::new("schedule table")
+                    .striped(true)
+                    .show(ui, |ui| {
+                        for header in ["layer", "z", "now", "scheduled"] {
+                            ui.label(header);
+                        }
+                        ui.end_row();
+                        for (i, layer) in preview
+                            .0
+                            .iter()
+                            .enumerate()
+                            .skip(range.start)
+                            .take(range.len())
+                        {
+                            ui.label(i.to_string());
+                            ui.label(format!("{:.2}", layer.z));
+                            ui.label(value(layer.current));
+                            let effective = value(layer.effective());
+                            if layer.scheduled.is_some() && layer.scheduled != layer.current {
+                                ui.strong(effective);
+                            } else {
+                                ui.label(effective);
+                            }
+                            ui.end_row();
+                        }
+                    });
+            },
+        );
+    });
+    ui_res.windows.schedule = open;
+}
+
+pub fn affine_window(
+    mut contexts: EguiContexts,
+    mut commands: Commands,
+    mut ui_res: ResMut<UiResource>,
+) {
+    let mut open = ui_res.windows.transform;
+    let window = egui::Window::new("Transform").open(&mut open);
+    window.show(contexts.ctx_mut(), |ui| {
+        ui.horizontal(|ui| {
+            let affine = &mut ui_res.affine;
+            if ui
+                .radio(matches!(affine, Affine::Mirror { .. }), "mirror")
+                .clicked()
+            {
+                *affine = Affine::Mirror { x: true, y: false };
+            }
+            if ui
+                .radio(matches!(affine, Affine::Scale(_)), "scale")
+                .clicked()
+            {
+                *affine = Affine::Scale(Vec3::ONE);
+            }
+            if ui
+                .radio(matches!(affine, Affine::Shear { .. }), "shear")
+                .clicked()
+            {
+                *affine = Affine::Shear {
+                    xy: 0.0,
+                    xz: 0.0,
+                    yz: 0.0,
+                };
+            }
+            if ui
+                .radio(matches!(affine, Affine::Matrix(_)), "matrix")
+                .clicked()
+            {
+                *affine = Affine::Matrix(Mat4::IDENTITY);
+            }
+        });
+        match &mut ui_res.affine {
+            Affine::Mirror { x, y } => {
+                ui.horizontal(|ui| {
+                    ui.checkbox(x, "x");
+                    ui.checkbox(y, "y");
+                });
+            }
+            Affine::Scale(scale) => {
+                ui.horizontal(|ui| {
+                    ui.add(egui::DragValue::new(&mut scale.x).speed(0.01).prefix("x "));
+                    ui.add(egui::DragValue::new(&mut scale.y).speed(0.01).prefix("y "));
+                    ui.add(egui::DragValue::new(&mut scale.z).speed(0.01).prefix("z "));
+                });
+            }
+            Affine::Shear { xy, xz, yz } => {
+                ui.horizontal(|ui| {
+                    ui.add(egui::DragValue::new(xy).speed(0.01).prefix("x/y "));
+                    ui.add(egui::DragValue::new(xz).speed(0.01).prefix("x/z "));
+                    ui.add(egui::DragValue::new(yz).speed(0.01).prefix("y/z "));
+                });
+            }
+            Affine::Matrix(matrix) => {
+                // shown row by row, glam stores columns
+                let mut cols = matrix.to_cols_array_2d();
+                egui::Grid::new("affine matrix").show(ui, |ui| {
+                    for row in 0..4 {
+                        for col in &mut cols {
+                            ui.add(egui::DragValue::new(&mut col[row]).speed(0.01));
+                        }
+                        ui.end_row();
+                    }
+                });
+                *matrix = Mat4::from_cols_array_2d(&cols);
+            }
+        }
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut ui_res.affine_preview, "preview");
+            if ui.button("Apply").clicked() {
+                commands.insert_resource(ApplyAffine(ui_res.affine));
+            }
+        });
+        ui.separator();
+        ui.label("whole print");
+        ui.horizontal(|ui| {
+            let res = ui_res.as_mut();
+            ui.add(
+                egui::DragValue::new(&mut res.print_offset.0)
+                    .speed(0.5)
+                    .prefix("x "),
+            );
+            ui.add(
+                egui::DragValue::new(&mut res.print_offset.1)
+                    .speed(0.5)
+                    .prefix("y "),
+            );
+            ui.add(
+                egui::DragValue::new(&mut res.print_angle)
+                    .clamp_range(-180.0..=180.0)
+                    .suffix("°"),
+            );
+            if ui.button("Move print").clicked() {
+                commands.insert_resource(MovePrint {
+                    dx: res.print_offset.0,
+                    dy: res.print_offset.1,
+                    angle: res.print_angle,
+                });
+            }
+        });
+    });
+    ui_res.windows.transform = open;
+}
+
+pub fn deform_window(
+    mut contexts: EguiContexts,
+    mut commands: Commands,
+    mut ui_res: ResMut<UiResource>,
+    result: Option<Res<DeformResult>>,
+) {
+    let mut open = ui_res.windows.non_planar;
+    let window = egui::Window::new("Non-planar").open(&mut open);
+    window.show(contexts.ctx_mut(), |ui| {
+        let deform = &mut ui_res.deform;
+        ui.horizontal(|ui| {
+            ui.radio_value(&mut deform.kind, SurfaceKind::Sine, "sine");
+            ui.radio_value(&mut deform.kind, SurfaceKind::HeightMap, "height map");
+            ui.radio_value(&mut deform.kind, SurfaceKind::Expression, "expression");
+        });
+        match deform.kind {
+            SurfaceKind::Sine => {
+                ui.horizontal(|ui| {
+                    ui.add(
+                        egui::DragValue::new(&mut deform.amplitude)
+                            .speed(0.05)
+                            .suffix(" mm"),
+                    );
+                    ui.label("amplitude");
+                });
+                ui.horizontal(|ui| {
+                    ui.add(
+                        egui::DragValue::new(&mut deform.wavelength)
+                            .speed(0.5)
+                            .clamp_range(0.1..=f32::MAX)
+                            .suffix(" mm"),
+                    );
+                    ui.label("wavelength");
+                    ui.add(egui::DragValue::new(&mut deform.angle).suffix("°"));
+                });
+            }
+            SurfaceKind::HeightMap => {
+                ui.text_edit_singleline(&mut deform.image)
+                    .on_hover_text("path to a png, white is raised the most");
+                ui.horizontal(|ui| {
+                    let extent = &mut deform.extent;
+                    ui.add(egui::DragValue::new(&mut extent.0).prefix("x "));
+                    ui.add(egui::DragValue::new(&mut extent.1).prefix("y "));
+                    ui.label("to");
+                    ui.add(egui::DragValue::new(&mut extent.2).prefix("x "));
+                    ui.add(egui::DragValue::new(&mut extent.3).prefix("y "));
+                });
+                ui.horizontal(|ui| {
+                    ui.add(
+                        egui::DragValue::new(&mut deform.amplitude)
+                            .speed(0.05)
+                            .suffix(" mm"),
+                    );
+                    ui.label("amplitude");
+                });
+            }
+            SurfaceKind::Expression => {
+                ui.text_edit_singleline(&mut deform.expression)
+                    .on_hover_text("z offset in mm as a formula of x and y");
+            }
+        }
+        ui.horizontal(|ui| {
+            ui.add(
+                egui::DragValue::new(&mut deform.max_segment)
+                    .speed(0.1)
+                    .clamp_range(0.1..=f32::MAX)
+                    .suffix(" mm"),
+            );
+            ui.label("longest segment");
+        });
+        if ui.button("Deform selection").clicked() {
+            commands.init_resource::<DeformSelection>();
+        }
+        if let Some(result) = result {
+            ui.label(format!(
+                "{} moves deformed, {} added",
+                result.0.moved, result.0.added
+            ));
+            if !result.0.violations.is_empty() {
+                ui.horizontal(|ui| {
+                    ui.label(format!(
+                        "{} moves too close to the print below",
+                        result.0.violations.len()
+                    ));
+                    if ui.button("Select").clicked() {
+                        commands.insert_resource(SelectIds(
+                            result.0.violations.iter().map(|c| c.id).collect(),
+                        ));
+                    }
+                });
+            }
+        }
+    });
+    ui_res.windows.non_planar = open;
+}
+
+pub fn seams_window(
+    mut contexts: EguiContexts,
+    mut commands: Commands,
+    mut ui_res: ResMut<UiResource>,
+    gcode: Res<GCode>,
+    analysis: Option<Res<SeamAnalysis>>,
+) {
+    let mut open = ui_res.windows.seams;
+    let window = egui::Window::new("Seams").open(&mut open);
+    window.show(contexts.ctx_mut(), |ui| {
+        ui.horizontal(|ui| {
+            if ui.button("Analyze").clicked() {
+                commands.init_resource::<AnalyzeSeams>();
+            }
+            if ui.button("Select seams").clicked() {
+                commands.insert_resource(SelectIds(gcode.0.seam_vertices().into_iter().collect()));
+            }
+            if ui.button("Start loops at selection").clicked() {
+                commands.init_resource::<SeamsToSelection>();
+            }
+        });
+        ui.horizontal(|ui| {
+            let align = &mut ui_res.seam_alignment;
+            ui.radio_value(align, SeamAlignment::Rear, "rear");
+            ui.radio_value(align, SeamAlignment::SharpestCorner, "sharpest corner");
+            if ui
+                .radio(matches!(align, SeamAlignment::Line(..)), "line")
+                .clicked()
+            {
+                *align = SeamAlignment::Line((0.0, 0.0), (0.0, 1.0));
+            }
+        });
+        if let SeamAlignment::Line(a, b) = &mut ui_res.seam_alignment {
+            ui.horizontal(|ui| {
+                for v in [&mut a.0, &mut a.1, &mut b.0, &mut b.1] {
+                    ui.add(egui::DragValue::new(v).speed(0.5));
+                }
+            });
+        }
+        if ui.button("Align seams").clicked() {
+            commands.insert_resource(AlignSeams(ui_res.seam_alignment));
+        }
+        let Some(analysis) = analysis else {
+            return;
+        };
+        let row_height = ui.text_style_height(&egui::TextStyle::Body);
+        egui::ScrollArea::vertical().max_height(300.0).show_rows(
+            ui,
+            row_height,
+            analysis.0.len(),
+            |ui, range| {
+                egui::Grid::new("seam table").striped(true).show(ui, |ui| {
+                    for header in ["object", "layers", "spread", "step", "aligned"] {
+                        ui.label(header);
+                    }
+                    ui.end_row();
+                    for s in &analysis.0[range] {
+                        ui.label(format!("({:.1}, {:.1})", s.center.0, s.center.1));
+                        ui.label(s.layers.to_string());
+                        ui.label(format!("{:.2}mm", s.spread));
+                        ui.label(format!("{:.2}mm", s.mean_step));
+                        ui.label(format!("{:.0}%", s.aligned * 100.0));
+                        ui.end_row();
+                    }
+                });
+            },
+        );
+    });
+    ui_res.windows.seams = open;
+}
+
+pub fn save_warning_window(
+    mut contexts: EguiContexts,
+    mut commands: Commands,
+    warning: Res<SaveWarning>,
+) {
+    egui::Window::new("Outside build volume")
+        .collapsible(false)
+        .show(contexts.ctx_mut(), |ui| {
+            ui.label(format!(
+                "{} moves are outside the build volume",
+                warning.0.len()
+            ));
+            out_of_bounds_rows(ui, &warning.0);
+            ui.horizontal(|ui| {
+                if ui.button("Save anyway").clicked() {
+                    commands.insert_resource(Save { force: true });
+                    commands.remove_resource::<SaveWarning>();
+                }
+                if ui.button("Cancel").clicked() {
+                    commands.remove_resource::<SaveWarning>();
+                }
+            });
+        });
+}
+
+pub fn move_print_warning_window(
+    mut contexts: EguiContexts,
+    mut commands: Commands,
+    warning: Res<MovePrintWarning>,
+) {
+    egui::Window::new("Print not moved")
+        .collapsible(false)
+        .show(contexts.ctx_mut(), |ui| {
+            ui.label(format!("{} moves would be off the bed", warning.0.len()));
+            out_of_bounds_rows(ui, &warning.0);
+            if ui.button("Close").clicked() {
+                commands.remove_resource::<MovePrintWarning>();
+            }
+        });
+}
+
+// one row per move, scrolling once there are more than fit
+fn out_of_bounds_rows(ui: &mut egui::Ui, report: &[OutOfBounds]) {
+    let row_height = ui.text_style_height(&egui::TextStyle::Body);
+    egui::ScrollArea::vertical().max_height(200.0).show_rows(
+        ui,
+        row_height,
+        report.len(),
+        |ui, rows| {
+            for v in &report[rows] {
+                let kind = match v.kind {
+                    BoundsKind::OffBed => "off the bed",
+                    BoundsKind::BelowBed => "below the bed",
+                    BoundsKind::AboveTop => "above the build height",
+                };
+                ui.label(format!("line {}: {}", v.line, kind));
+            }
+        },
+    );
+}
+
 #[derive(Resource)]
 pub struct VertexCounter {
     max: u32,
@@ -345,8 +1524,12 @@ pub fn key_system(
     mut ui_res: ResMut<UiResource>,
     mut keys: ResMut<ButtonInput<KeyCode>>,
     mut log: ResMut<SelectionLog>,
+    mut gcode_log: ResMut<GCodeLog>,
     settings: Res<Settings>,
 ) {
+    let ctrl = keys.any_pressed([KeyCode::ControlRight, KeyCode::ControlLeft]);
+    let alt = keys.any_pressed([KeyCode::AltRight, KeyCode::AltLeft]);
+    let shift = keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]);
     if keys.pressed(KeyCode::ArrowLeft) {
         if ui_res.vertex_counter == 0 {
             return;
@@ -358,31 +1541,40 @@ pub fn key_system(
         ui_res.display_z_max.0 += 0.2;
     } else if keys.pressed(KeyCode::ArrowDown) {
         ui_res.display_z_max.0 -= 0.2;
-    } else if keys.any_pressed([KeyCode::ControlRight, KeyCode::ControlLeft])
-        && keys.just_pressed(KeyCode::KeyZ)
-        && !keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight])
-    {
-        if log.history_counter as usize >= log.log.len() {
-            return;
+    } else if ctrl && keys.just_pressed(KeyCode::KeyZ) && !shift {
+        // ctrl+alt+z steps through selections, ctrl+z through edits
+        if alt {
+            if log.history_counter as usize >= log.log.len() {
+                return;
+            }
+            log.history_counter += 1;
+            commands.init_resource::<SetSelections>();
+        } else {
+            if gcode_log.history_counter as usize >= gcode_log.len() {
+                return;
+            }
+            gcode_log.history_counter += 1;
+            commands.init_resource::<UndoRedoGCode>();
         }
-        log.history_counter += 1;
-        commands.init_resource::<SetSelections>();
-    } else if keys.any_pressed([KeyCode::ControlRight, KeyCode::ControlLeft])
-        && keys.just_pressed(KeyCode::KeyZ)
-        && keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight])
-    {
-        if log.history_counter == 0 {
-            return;
+    } else if ctrl && keys.just_pressed(KeyCode::KeyZ) && shift {
+        if alt {
+            if log.history_counter == 0 {
+                return;
+            }
+            log.history_counter -= 1;
+            commands.init_resource::<SetSelections>();
+        } else {
+            if gcode_log.history_counter == 0 {
+                return;
+            }
+            gcode_log.history_counter -= 1;
+            commands.init_resource::<UndoRedoGCode>();
         }
-        log.history_counter -= 1;
-        commands.init_resource::<SetSelections>();
     } else if keys.just_pressed(settings.hole_delete_button) {
         commands.init_resource::<HoleDelete>();
     } else if keys.just_pressed(settings.merge_delete_button) {
         commands.init_resource::<MergeDelete>();
-    } else if keys.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight])
-        && keys.just_pressed(KeyCode::KeyR)
-    {
+    } else if ctrl && keys.just_pressed(KeyCode::KeyR) {
         commands.init_resource::<ForceRefresh>();
     }
     // clear key presses after read